@@ -227,6 +227,7 @@ pub fn run(
             )
             .await
         };
+        let output = output.map_err(|err| JsValue::from_str(&err.to_string()))?;
         Ok(JsValue::from_serde(&output).unwrap())
     })
 }
@@ -73,6 +73,9 @@ struct Input {
     r2_lang: Lang,
     board_id: usize,
     game_mode: logic::GameMode,
+    /// Match settings such as the grid size and map shape; the defaults are used if missing
+    #[serde(default)]
+    settings: Option<logic::Settings>,
 }
 
 #[derive(Serialize, Debug)]
@@ -218,13 +221,13 @@ async fn run(data: LambdaInput, _ctx: lambda::Context) -> Result<(), Error> {
         |_| {},
         input_data.turn_num,
         false,
-        None,
+        input_data.settings,
         input_data.game_mode,
         None,
     );
 
     let output = tokio::select! {
-        output = run_fut => output,
+        output = run_fut => output?,
         Err(err) = t1 => panic!("t1 error: {:?}", err),
         Err(err) = t2 => panic!("t2 error: {:?}", err),
         else => panic!("Runner executed earlier than logic")
@@ -236,7 +239,8 @@ async fn run(data: LambdaInput, _ctx: lambda::Context) -> Result<(), Error> {
     let winner = match output.winner {
         Some(Team::Blue) => Some(OutputTeam::R1),
         Some(Team::Red) => Some(OutputTeam::R2),
-        None => None,
+        // lambda matches are always between the two teams above
+        Some(_) | None => None,
     };
     let errored = !output.errors.is_empty();
 
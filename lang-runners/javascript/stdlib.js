@@ -48,16 +48,7 @@ class Enum {
 
 class Direction extends Enum {
   get opposite() {
-    switch (this) {
-      case Direction.East:
-        return Direction.West
-      case Direction.West:
-        return Direction.East
-      case Direction.North:
-        return Direction.South
-      case Direction.South:
-        return Direction.North
-    }
+    return this.rotateCw.rotateCw
   }
 
   get toCoords() {
@@ -70,9 +61,22 @@ class Direction extends Enum {
         return new Coords(0, -1)
       case Direction.South:
         return new Coords(0, 1)
+      case Direction.NorthEast:
+        return new Coords(1, -1)
+      case Direction.SouthEast:
+        return new Coords(1, 1)
+      case Direction.SouthWest:
+        return new Coords(-1, 1)
+      case Direction.NorthWest:
+        return new Coords(-1, -1)
     }
   }
 
+  get isDiagonal() {
+    const coords = this.toCoords
+    return coords.x !== 0 && coords.y !== 0
+  }
+
   get rotateCw() {
     switch (this) {
       case Direction.North:
@@ -83,26 +87,30 @@ class Direction extends Enum {
         return Direction.West
       case Direction.West:
         return Direction.North
+      case Direction.NorthEast:
+        return Direction.SouthEast
+      case Direction.SouthEast:
+        return Direction.SouthWest
+      case Direction.SouthWest:
+        return Direction.NorthWest
+      case Direction.NorthWest:
+        return Direction.NorthEast
     }
   }
 
   get rotateCcw() {
-    switch (this) {
-      case Direction.North:
-        return Direction.West
-      case Direction.West:
-        return Direction.South
-      case Direction.South:
-        return Direction.East
-      case Direction.East:
-        return Direction.North
-    }
+    return this.rotateCw.opposite
   }
 }
 Direction.East = new Direction()
 Direction.West = new Direction()
 Direction.South = new Direction()
 Direction.North = new Direction()
+// the diagonals can only be used in matches where State.directions includes them
+Direction.NorthEast = new Direction()
+Direction.SouthEast = new Direction()
+Direction.SouthWest = new Direction()
+Direction.NorthWest = new Direction()
 Direction.closeEnum()
 
 class Coords {
@@ -135,6 +143,11 @@ class Coords {
     return Math.abs(other.x - this.x) + Math.abs(other.y - this.y)
   }
 
+  // only the cells in the directions that the match allows, see State.directions
+  coordsAround() {
+    return DIRECTIONS.map(direction => this.add(direction))
+  }
+
   directionTo(other) {
     checkInstance(other, Coords, 'Coords.directionTo')
     const diff = this.sub(other)
@@ -176,21 +189,27 @@ class Coords {
   }
 }
 
-const SPAWN_COORDS = new Set([new Coords(1, 5), new Coords(1, 6), new Coords(1, 7), new Coords(1, 8), new Coords(1, 9), new Coords(1, 10), new Coords(1, 11), new Coords(1, 12), new Coords(1, 13), new Coords(2, 4), new Coords(2, 14), new Coords(3, 3), new Coords(3, 15), new Coords(4, 2), new Coords(4, 16), new Coords(5, 1), new Coords(5, 17), new Coords(6, 1), new Coords(6, 17), new Coords(7, 1), new Coords(7, 17), new Coords(8, 1), new Coords(8, 17), new Coords(9, 1), new Coords(9, 17), new Coords(10, 1), new Coords(10, 17), new Coords(11, 1), new Coords(11, 17), new Coords(12, 1), new Coords(12, 17), new Coords(13, 1), new Coords(13, 17), new Coords(14, 2), new Coords(14, 16), new Coords(15, 3), new Coords(15, 15), new Coords(16, 4), new Coords(16, 14), new Coords(17, 5), new Coords(17, 6), new Coords(17, 7), new Coords(17, 8), new Coords(17, 9), new Coords(17, 10), new Coords(17, 11), new Coords(17, 12), new Coords(17, 13)])
-const SPAWN_COORDS_STRINGS = new Set([...SPAWN_COORDS].map(coords => coords.toString()))
-
-const HILL_COORDS = new Set([new Coords(9, 9), new Coords(8, 9), new Coords(8, 8), new Coords(9, 8), new Coords(10, 8), new Coords(10, 9), new Coords(10, 10), new Coords(9, 10), new Coords(8, 10)])
-const HILL_COORDS_STRINGS = new Set([...HILL_COORDS].map(coords => coords.toString()))
+// filled in from the input each turn, see __main
+let SPAWN_COORDS_STRINGS = new Set()
+let HILL_COORDS_STRINGS = new Set()
+let MAP_SIZE = 19
+let DIRECTIONS = Direction.enumValues.filter(direction => !direction.isDiagonal)
 
 class Team extends Enum {
   get opposite() {
     if (this === Team.Red) {
       return Team.Blue
-    } else return Team.Red
+    } else if (this === Team.Blue) {
+      return Team.Red
+    } else {
+      throw new TypeError(`${this} has no opposite team`)
+    }
   }
 }
 Team.Red = new Team()
 Team.Blue = new Team()
+Team.Green = new Team()
+Team.Yellow = new Team()
 Team.closeEnum()
 
 class ObjType extends Enum { }
@@ -198,6 +217,65 @@ ObjType.Unit = new ObjType()
 ObjType.Terrain = new ObjType()
 ObjType.closeEnum()
 
+class TerrainType extends Enum { }
+TerrainType.Wall = new TerrainType()
+TerrainType.Swamp = new TerrainType()
+TerrainType.Fountain = new TerrainType()
+TerrainType.Lava = new TerrainType()
+TerrainType.Cover = new TerrainType()
+TerrainType.Resource = new TerrainType()
+TerrainType.closeEnum()
+
+class UnitType extends Enum { }
+UnitType.Soldier = new UnitType()
+UnitType.Archer = new UnitType()
+UnitType.Tank = new UnitType()
+UnitType.Medic = new UnitType()
+UnitType.closeEnum()
+
+class UnitStats {
+  constructor(stats) {
+    checkType(stats, 'object', 'UnitStats constructor')
+    this.__data = stats
+  }
+
+  toString() {
+    return `<UnitStats health=${this.health} maxHealth=${this.maxHealth} attackPower=${this.attackPower} attackRange=${this.attackRange} healPower=${this.healPower} moveEvery=${this.moveEvery} cost=${this.cost}>`
+  }
+
+  get health() {
+    return this.__data.health
+  }
+
+  get maxHealth() {
+    return this.__data.max_health
+  }
+
+  get attackPower() {
+    return this.__data.attack_power
+  }
+
+  get attackRange() {
+    return this.__data.attack_range
+  }
+
+  get healPower() {
+    return this.__data.heal_power
+  }
+
+  get moveEvery() {
+    return this.__data.move_every
+  }
+
+  get cost() {
+    return this.__data.cost
+  }
+
+  get actions() {
+    return this.__data.actions.map(action => ActionType.valueOf(action))
+  }
+}
+
 class Obj {
   constructor(obj) {
     checkType(obj, 'object', 'Obj constructor')
@@ -206,7 +284,7 @@ class Obj {
 
   toString() {
     if (this.objType === ObjType.Unit)
-      return `<${this.objType} id=${this.id} coords=${this.coords} ${this.team} health=${this.health}>`
+      return `<${this.objType} id=${this.id} coords=${this.coords} ${this.team} ${this.unitType} health=${this.health}>`
     else
       return `<${this.objType} id=${this.id} coords=${this.coords}>`
   }
@@ -229,29 +307,76 @@ class Obj {
     }
   }
 
-  get health() {
+  get terrainType() {
+    if (this.objType === ObjType.Terrain) {
+      return TerrainType.valueOf(this.__data.type)
+    }
+  }
+
+  get unitType() {
     if (this.objType === ObjType.Unit) {
-      return this.__data.health
+      return UnitType.valueOf(this.__data.type)
     }
   }
+
+  // terrain only has health if it can be destroyed
+  get health() {
+    return this.__data.health ?? undefined
+  }
 }
 
 class State {
   constructor(state) {
     checkType(state, 'object', 'State constructor')
     this.__data = state
+    this.__spawnOrder = null
   }
 
   get turn() {
     return this.__data.turn
   }
 
+  get gridSize() {
+    return this.__data.grid_size
+  }
+
+  get directions() {
+    const all = Direction.enumValues
+    if (this.__data.directions === 'Eight') return all
+    return all.filter(direction => !direction.isDiagonal)
+  }
+
   get ourTeam() {
     return Team.valueOf(this.__data.team)
   }
 
+  get teams() {
+    return Object.keys(this.__data.teams).map(team => Team.valueOf(team))
+  }
+
+  get otherTeams() {
+    return this.teams.filter(team => team !== this.ourTeam)
+  }
+
   get otherTeam() {
-    return this.ourTeam.opposite
+    const otherTeams = this.otherTeams
+    if (otherTeams.length !== 1) {
+      throw new TypeError('otherTeam is only available in 2 team matches, use otherTeams instead')
+    }
+    return otherTeams[0]
+  }
+
+  get spawnCoords() {
+    return this.__data.spawn_points.map(coords => new Coords(...coords))
+  }
+
+  get hillCoords() {
+    return this.__data.hill.map(coords => new Coords(...coords))
+  }
+
+  unitStats(unitType) {
+    checkInstance(unitType, UnitType, 'State.unitStats')
+    return new UnitStats(this.__data.unit_stats[unitType.enumKey])
   }
 
   objById(id) {
@@ -260,6 +385,70 @@ class State {
     if (obj) return new Obj(obj)
   }
 
+  hillScore(team) {
+    checkInstance(team, Team, 'State.hillScore')
+    return this.__data.hill_score[team.enumKey] || 0
+  }
+
+  // the flag methods return null outside of capture the flag matches, and under fog of war
+  // flagCoords and flagCarrier return null while the carrier is out of sight
+  flagCoords(team) {
+    checkInstance(team, Team, 'State.flagCoords')
+    const flag = this.__data.flags[team.enumKey]
+    return flag && flag.coords ? new Coords(...flag.coords) : null
+  }
+
+  flagBase(team) {
+    checkInstance(team, Team, 'State.flagBase')
+    const flag = this.__data.flags[team.enumKey]
+    return flag ? new Coords(...flag.base) : null
+  }
+
+  flagCarrier(team) {
+    checkInstance(team, Team, 'State.flagCarrier')
+    const flag = this.__data.flags[team.enumKey]
+    return flag ? flag.carrier : null
+  }
+
+  captures(team) {
+    checkInstance(team, Team, 'State.captures')
+    return this.__data.captures[team.enumKey] || 0
+  }
+
+  // in battle royale matches, the walls close in one ring of cells at a time, starting from the edge of the grid.
+  // the zone methods treat every cell as open in the other modes
+  ring(coords) {
+    checkInstance(coords, Coords, 'State.ring')
+    return Math.min(coords.x, coords.y, this.gridSize - 1 - coords.x, this.gridSize - 1 - coords.y)
+  }
+
+  isClosed(coords) {
+    checkInstance(coords, Coords, 'State.isClosed')
+    const zone = this.__data.zone
+    return zone != null && this.ring(coords) < zone.closed_rings
+  }
+
+  // the turn that the cell at coords will be walled off, or null if it's already closed or never will be
+  closingTurn(coords) {
+    checkInstance(coords, Coords, 'State.closingTurn')
+    const zone = this.__data.zone
+    if (zone == null) return null
+    return zone.upcoming[this.ring(coords) - zone.closed_rings] ?? null
+  }
+
+  resources(team) {
+    checkInstance(team, Team, 'State.resources')
+    return this.__data.resources[team.enumKey] || 0
+  }
+
+  // in economy matches, the units to buy at the next spawn wave, replacing any earlier order.
+  // buying stops at the first unit that the team can't afford
+  orderSpawns(unitTypes) {
+    checkInstance(unitTypes, Array, 'State.orderSpawns')
+    unitTypes.forEach(unitType => checkInstance(unitType, UnitType, 'State.orderSpawns'))
+    this.__spawnOrder = [...unitTypes]
+  }
+
   idsByTeam(team) {
     checkInstance(team, Team, 'State.idsByTeam')
     return this.__data.teams[team.enumKey]
@@ -280,12 +469,20 @@ class State {
     const id = this.idByCoords(coords)
     if (id) return this.objById(id)
   }
+
+  // the terrain at coords that units can stand on, like a swamp. walls are returned by objByCoords instead
+  terrainByCoords(coords) {
+    checkInstance(coords, Coords, 'State.terrainByCoords')
+    const id = this.__data.terrain_grid[coords.y]?.[coords.x]
+    if (id) return this.objById(id)
+  }
 }
 
 class ActionType extends Enum { }
 ActionType.Attack = new ActionType()
 ActionType.Move = new ActionType()
 ActionType.Heal = new ActionType()
+ActionType.Harvest = new ActionType()
 ActionType.closeEnum()
 
 class Action {
@@ -314,10 +511,13 @@ class Action {
     checkInstance(direction, Direction, 'Action.heal')
     return new Action(ActionType.Heal, direction)
   }
-}
 
+  static harvest(direction) {
+    checkInstance(direction, Direction, 'Action.harvest')
+    return new Action(ActionType.Harvest, direction)
+  }
+}
 
-const MAP_SIZE = 19
 
 
 function __format_err(err, isInitError = false) {
@@ -370,6 +570,10 @@ function __main(stateData) {
   }
 
   const state = new State(stateData)
+  SPAWN_COORDS_STRINGS = new Set(state.spawnCoords.map(coords => coords.toString()))
+  HILL_COORDS_STRINGS = new Set(state.hillCoords.map(coords => coords.toString()))
+  MAP_SIZE = state.gridSize
+  DIRECTIONS = state.directions
 
   try {
     __validateFunction('robot', globalThis.robot, 2, true)
@@ -424,5 +628,6 @@ function __main(stateData) {
     robot_actions[id] = result
     if (Object.keys(debug_inspect_table).length) debug_inspect_tables[id] = debug_inspect_table
   }
-  return { Ok: { robot_actions, logs, debug_inspect_tables, debug_locate_queries } }
+  const spawn_order = state.__spawnOrder
+  return { Ok: { robot_actions, logs, debug_inspect_tables, debug_locate_queries, spawn_order } }
 }
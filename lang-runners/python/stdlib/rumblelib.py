@@ -14,17 +14,17 @@ class Direction(enum.Enum):
     South = "South"
     East = "East"
     West = "West"
+    # the diagonals can only be used in matches where State.directions includes them
+    NorthEast = "NorthEast"
+    SouthEast = "SouthEast"
+    SouthWest = "SouthWest"
+    NorthWest = "NorthWest"
 
     __repr__ = lambda self: self.__str__()
 
     @property
     def opposite(self) -> "Direction":
-        return {
-            Direction.East: Direction.West,
-            Direction.West: Direction.East,
-            Direction.South: Direction.North,
-            Direction.North: Direction.South,
-        }[self]
+        return self.rotate_cw.rotate_cw
 
     @property
     def to_coords(self) -> "Coords":
@@ -33,8 +33,16 @@ class Direction(enum.Enum):
             Direction.West: Coords(-1, 0),
             Direction.South: Coords(0, 1),
             Direction.North: Coords(0, -1),
+            Direction.NorthEast: Coords(1, -1),
+            Direction.SouthEast: Coords(1, 1),
+            Direction.SouthWest: Coords(-1, 1),
+            Direction.NorthWest: Coords(-1, -1),
         }[self]
 
+    @property
+    def is_diagonal(self) -> bool:
+        return self.to_coords.x != 0 and self.to_coords.y != 0
+
     @property
     def rotate_cw(self) -> "Direction":
         return {
@@ -42,16 +50,15 @@ class Direction(enum.Enum):
             Direction.East: Direction.South,
             Direction.South: Direction.West,
             Direction.West: Direction.North,
+            Direction.NorthEast: Direction.SouthEast,
+            Direction.SouthEast: Direction.SouthWest,
+            Direction.SouthWest: Direction.NorthWest,
+            Direction.NorthWest: Direction.NorthEast,
         }[self]
 
     @property
     def rotate_ccw(self) -> "Direction":
-        return {
-            Direction.North: Direction.West,
-            Direction.West: Direction.South,
-            Direction.South: Direction.East,
-            Direction.East: Direction.North,
-        }[self]
+        return self.rotate_cw.opposite
 
 class Coords(tuple):
     def __new__(cls, x: int, y: int) -> "Coords":
@@ -71,11 +78,11 @@ class Coords(tuple):
     def y(self) -> int:
         return self[1]
 
-    def is_spawn(self) -> int:
-        return str(self) in SPAWN_COORDS_STRINGS
+    def is_spawn(self) -> bool:
+        return self in SPAWN_COORDS
 
-    def is_hill(self) -> int:
-        return str(self) in HILL_COORDS_STRINGS
+    def is_hill(self) -> bool:
+        return self in HILL_COORDS
 
     def distance_to(self, other: "Coords") -> float:
         import math
@@ -86,8 +93,9 @@ class Coords(tuple):
         check_instance(other, Coords, "Coords.walking_distance_to")
         return abs(other.x - self.x) + abs(other.y - self.y)
 
+    # only the cells in the directions that the match allows, see State.directions
     def coords_around(self) -> typing.List["Coords"]:
-        return [self + direction for direction in Direction]
+        return [self + direction for direction in DIRECTIONS]
 
     def direction_to(self, other: "Coords") -> Direction:
         import math
@@ -123,15 +131,17 @@ class Coords(tuple):
         check_instance(n, int, "Coords.__mul__")
         return Coords(self.x * n, self.y * n)
 
-SPAWN_COORDS = set([Coords(1, 5), Coords(1, 6), Coords(1, 7), Coords(1, 8), Coords(1, 9), Coords(1, 10), Coords(1, 11), Coords(1, 12), Coords(1, 13), Coords(2, 4), Coords(2, 14), Coords(3, 3), Coords(3, 15), Coords(4, 2), Coords(4, 16), Coords(5, 1), Coords(5, 17), Coords(6, 1), Coords(6, 17), Coords(7, 1), Coords(7, 17), Coords(8, 1), Coords(8, 17), Coords(9, 1), Coords(9, 17), Coords(10, 1), Coords(10, 17), Coords(11, 1), Coords(11, 17), Coords(12, 1), Coords(12, 17), Coords(13, 1), Coords(13, 17), Coords(14, 2), Coords(14, 16), Coords(15, 3), Coords(15, 15), Coords(16, 4), Coords(16, 14), Coords(17, 5), Coords(17, 6), Coords(17, 7), Coords(17, 8), Coords(17, 9), Coords(17, 10), Coords(17, 11), Coords(17, 12), Coords(17, 13)])
-SPAWN_COORDS_STRINGS = map(str, SPAWN_COORDS)
-
-HILL_COORDS = set([Coords(9, 9), Coords(8, 9), Coords(8, 8), Coords(9, 8), Coords(10, 8), Coords(10, 9), Coords(10, 10), Coords(9, 10), Coords(8, 10)])
-HILL_COORDS_STRINGS = map(str, SPAWN_COORDS)
+# filled in from the input each turn, see __main
+SPAWN_COORDS = set()
+HILL_COORDS = set()
+MAP_SIZE = 19
+DIRECTIONS = [direction for direction in Direction if not direction.is_diagonal]
 
 class Team(enum.Enum):
     Red = "Red"
     Blue = "Blue"
+    Green = "Green"
+    Yellow = "Yellow"
 
     __repr__ = lambda self: self.__str__()
 
@@ -139,8 +149,10 @@ class Team(enum.Enum):
     def opposite(self) -> "Team":
         if self == Team.Red:
             return Team.Blue
-        else:
+        elif self == Team.Blue:
             return Team.Red
+        else:
+            raise ValueError(f"{self} has no opposite team")
 
 
 class ObjType(enum.Enum):
@@ -150,6 +162,67 @@ class ObjType(enum.Enum):
     __repr__ = lambda self: self.__str__()
 
 
+class TerrainType(enum.Enum):
+    Wall = "Wall"
+    Swamp = "Swamp"
+    Fountain = "Fountain"
+    Lava = "Lava"
+    Cover = "Cover"
+    Resource = "Resource"
+
+    __repr__ = lambda self: self.__str__()
+
+
+class UnitType(enum.Enum):
+    Soldier = "Soldier"
+    Archer = "Archer"
+    Tank = "Tank"
+    Medic = "Medic"
+
+    __repr__ = lambda self: self.__str__()
+
+
+class UnitStats:
+    def __init__(self, stats: dict) -> None:
+        check_instance(stats, dict, "UnitStats.__init__")
+        self.__data = stats
+
+    def __repr__(self) -> str:
+        return f"<UnitStats health={self.health} max_health={self.max_health} attack_power={self.attack_power} attack_range={self.attack_range} heal_power={self.heal_power} move_every={self.move_every} cost={self.cost}>"
+
+    @property
+    def health(self) -> int:
+        return self.__data["health"]
+
+    @property
+    def max_health(self) -> int:
+        return self.__data["max_health"]
+
+    @property
+    def attack_power(self) -> int:
+        return self.__data["attack_power"]
+
+    @property
+    def attack_range(self) -> int:
+        return self.__data["attack_range"]
+
+    @property
+    def heal_power(self) -> int:
+        return self.__data["heal_power"]
+
+    @property
+    def move_every(self) -> int:
+        return self.__data["move_every"]
+
+    @property
+    def cost(self) -> int:
+        return self.__data["cost"]
+
+    @property
+    def actions(self) -> typing.List["ActionType"]:
+        return [ActionType(action) for action in self.__data["actions"]]
+
+
 class Obj:
     def __init__(self, obj: dict) -> None:
         check_instance(obj, dict, "Coords.__init__")
@@ -157,7 +230,7 @@ class Obj:
 
     def __repr__(self) -> str:
         if self.obj_type == ObjType.Unit:
-            return f"<{self.obj_type} id={self.id} coords={self.coords} {self.team} health={self.health}>"
+            return f"<{self.obj_type} id={self.id} coords={self.coords} {self.team} {self.unit_type} health={self.health}>"
         else:
             return f"<{self.obj_type} id={self.id} coords={self.coords}>"
 
@@ -181,29 +254,76 @@ class Obj:
             return None
 
     @property
-    def health(self) -> typing.Optional[int]:
+    def terrain_type(self) -> typing.Optional[TerrainType]:
+        if self.obj_type == ObjType.Terrain:
+            return TerrainType(self.__data["type"])
+        else:
+            return None
+
+    @property
+    def unit_type(self) -> typing.Optional[UnitType]:
         if self.obj_type == ObjType.Unit:
-            return self.__data["health"]
+            return UnitType(self.__data["type"])
         else:
             return None
 
+    # terrain only has health if it can be destroyed
+    @property
+    def health(self) -> typing.Optional[int]:
+        return self.__data.get("health")
+
 
 class State:
     def __init__(self, state: dict) -> None:
         check_instance(state, dict, "State.__init__")
         self.__data = state
+        self._spawn_order = None
 
     @property
     def turn(self) -> int:
         return self.__data["turn"]
 
+    @property
+    def grid_size(self) -> int:
+        return self.__data["grid_size"]
+
+    @property
+    def directions(self) -> typing.List[Direction]:
+        if self.__data["directions"] == "Eight":
+            return list(Direction)
+        else:
+            return [d for d in Direction if not d.is_diagonal]
+
     @property
     def our_team(self) -> Team:
         return Team(self.__data["team"])
 
+    @property
+    def teams(self) -> typing.List[Team]:
+        return [Team(team) for team in self.__data["teams"]]
+
+    @property
+    def other_teams(self) -> typing.List[Team]:
+        return [team for team in self.teams if team != self.our_team]
+
     @property
     def other_team(self) -> Team:
-        return self.our_team.opposite
+        other_teams = self.other_teams
+        if len(other_teams) != 1:
+            raise ValueError("other_team is only available in 2 team matches, use other_teams instead")
+        return other_teams[0]
+
+    @property
+    def spawn_coords(self) -> typing.List[Coords]:
+        return [Coords(*coords) for coords in self.__data["spawn_points"]]
+
+    @property
+    def hill_coords(self) -> typing.List[Coords]:
+        return [Coords(*coords) for coords in self.__data["hill"]]
+
+    def unit_stats(self, unit_type: UnitType) -> UnitStats:
+        check_instance(unit_type, UnitType, 'State.unit_stats')
+        return UnitStats(self.__data["unit_stats"][unit_type.value])
 
     def obj_by_id(self, id: str) -> typing.Optional[Obj]:
         check_instance(id, str, 'State.obj_by_id')
@@ -212,6 +332,65 @@ class State:
         except KeyError:
             return None
 
+    def hill_score(self, team: Team) -> int:
+        check_instance(team, Team, 'State.hill_score')
+        return self.__data["hill_score"].get(team.value, 0)
+
+    # the flag functions return None outside of capture the flag matches, and under fog of war
+    # flag_coords and flag_carrier return None while the carrier is out of sight
+    def flag_coords(self, team: Team) -> typing.Optional[Coords]:
+        check_instance(team, Team, 'State.flag_coords')
+        flag = self.__data["flags"].get(team.value)
+        return Coords(*flag["coords"]) if flag and flag["coords"] else None
+
+    def flag_base(self, team: Team) -> typing.Optional[Coords]:
+        check_instance(team, Team, 'State.flag_base')
+        flag = self.__data["flags"].get(team.value)
+        return Coords(*flag["base"]) if flag else None
+
+    def flag_carrier(self, team: Team) -> typing.Optional[str]:
+        check_instance(team, Team, 'State.flag_carrier')
+        flag = self.__data["flags"].get(team.value)
+        return flag["carrier"] if flag else None
+
+    def captures(self, team: Team) -> int:
+        check_instance(team, Team, 'State.captures')
+        return self.__data["captures"].get(team.value, 0)
+
+    # in battle royale matches, the walls close in one ring of cells at a time, starting from the edge of the grid.
+    # the zone functions treat every cell as open in the other modes
+    def ring(self, coords: Coords) -> int:
+        check_instance(coords, Coords, 'State.ring')
+        return min(coords.x, coords.y, self.grid_size - 1 - coords.x, self.grid_size - 1 - coords.y)
+
+    def is_closed(self, coords: Coords) -> bool:
+        check_instance(coords, Coords, 'State.is_closed')
+        zone = self.__data["zone"]
+        return zone is not None and self.ring(coords) < zone["closed_rings"]
+
+    # the turn that the cell at coords will be walled off, or None if it's already closed or never will be
+    def closing_turn(self, coords: Coords) -> typing.Optional[int]:
+        check_instance(coords, Coords, 'State.closing_turn')
+        zone = self.__data["zone"]
+        if zone is None:
+            return None
+        index = self.ring(coords) - zone["closed_rings"]
+        if 0 <= index < len(zone["upcoming"]):
+            return zone["upcoming"][index]
+        return None
+
+    def resources(self, team: Team) -> int:
+        check_instance(team, Team, 'State.resources')
+        return self.__data["resources"].get(team.value, 0)
+
+    # in economy matches, the units to buy at the next spawn wave, replacing any earlier order.
+    # buying stops at the first unit that the team can't afford
+    def order_spawns(self, unit_types: typing.List[UnitType]) -> None:
+        check_instance(unit_types, list, 'State.order_spawns')
+        for unit_type in unit_types:
+            check_instance(unit_type, UnitType, 'State.order_spawns')
+        self._spawn_order = list(unit_types)
+
     def ids_by_team(self, team: Team) -> typing.List[str]:
         check_instance(team, Team, 'State.check_instance')
         return self.__data["teams"][team.value]
@@ -235,11 +414,24 @@ class State:
         else:
             return None
 
+    # the terrain at coords that units can stand on, like a swamp. walls are returned by obj_by_coords instead
+    def terrain_by_coords(self, coords: Coords) -> typing.Optional[Obj]:
+        check_instance(coords, Coords, 'State.terrain_by_coords')
+        try:
+            id = self.__data["terrain_grid"][coords.y][coords.x]
+        except IndexError:
+            return None
+        if id:
+            return self.obj_by_id(id)
+        else:
+            return None
+
 
 class ActionType(enum.Enum):
     Attack = "Attack"
     Move = "Move"
     Heal = "Heal"
+    Harvest = "Harvest"
 
     __repr__ = lambda self: self.__str__()
 
@@ -269,8 +461,11 @@ class Action:
         check_instance(direction, Direction, 'Action.heal')
         return Action(ActionType.Heal, direction)
 
+    @staticmethod
+    def harvest(direction: Direction) -> "Action":
+        check_instance(direction, Direction, 'Action.harvest')
+        return Action(ActionType.Harvest, direction)
 
-MAP_SIZE = 19
 
 def __format_err(exc):
     loc = None
@@ -314,6 +509,11 @@ def __main(state, scope=globals()):
     logbuf = sys.stdout = io.StringIO()
 
     state = State(state)
+    global SPAWN_COORDS, HILL_COORDS, MAP_SIZE, DIRECTIONS
+    SPAWN_COORDS = set(state.spawn_coords)
+    HILL_COORDS = set(state.hill_coords)
+    MAP_SIZE = state.grid_size
+    DIRECTIONS = state.directions
     try:
         robot = __validate_function("robot", 2, True)
         init_turn = __validate_function("init_turn", 1, False)
@@ -371,6 +571,7 @@ def __main(state, scope=globals()):
     logs = logbuf.readlines()
     logbuf.close()
 
+    spawn_order = state._spawn_order
     del state
 
     return {
@@ -378,7 +579,8 @@ def __main(state, scope=globals()):
             "robot_actions": robot_actions,
             "logs": logs,
             "debug_inspect_tables": debug_inspect_tables,
-            "debug_locate_queries": debug_locate_queries
+            "debug_locate_queries": debug_locate_queries,
+            "spawn_order": [t.value for t in spawn_order] if spawn_order is not None else None,
         }
     }
 
@@ -0,0 +1,712 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    determine_winner_from_scores, determine_winner_normal, handle_program_errors, is_id_valid,
+    run_turn, validate_robot_action, CallbackInput, EndOnElimination, GameMode, Id, MainOutput,
+    Obj, ObjDetails, ProgramError, ProgramInput, ProgramOutput, RobotErrorAfterValidation,
+    Settings, SettingsError, State, StateForOutput, Team, TeamStats, Termination, TurnState,
+    UnitType, ValidatedRobotAction,
+};
+
+/// Everything needed to set up a match
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MatchConfig {
+    pub teams: Vec<Team>,
+    pub max_turn: usize,
+    /// Whether to record the debug tables and locate queries that programs output
+    pub dev_mode: bool,
+    #[serde(default)]
+    pub settings: Settings,
+    pub game_mode: GameMode,
+    /// Picked at random when `None`. Either way, the seed is recorded in `MainOutput::seed`, so
+    /// that the match can be verified later.
+    #[serde(default)]
+    pub seed: Option<String>,
+}
+
+/// A match that is driven one turn at a time. Each turn, get every team's input with
+/// `current_input`, hand their outputs back with `submit`, and then `step` to play the turn.
+/// Teams that don't submit anything for a turn simply don't act. Once `is_finished` returns
+/// true (or earlier, to cut the match short), `finish` computes the result.
+///
+/// `logic::run` is a loop over this with a `RobotRunner` per team.
+pub struct Game {
+    config: MatchConfig,
+    turn_state: TurnState,
+    /// The actions, logs, etc. submitted so far for the current turn
+    pending: CallbackInput,
+    turns: Vec<CallbackInput>,
+    /// Whether the match ended early because of `Settings::end_on_elimination`
+    eliminated: bool,
+    stats: BTreeMap<Team, TeamStats>,
+}
+
+impl Game {
+    pub fn new(mut config: MatchConfig) -> Result<Self, SettingsError> {
+        if config.seed.is_none() {
+            config.seed = Some(format!("{:016x}", rand::random::<u64>()));
+        }
+        let mut state = State::new(
+            config.settings.clone(),
+            &config.teams,
+            config.seed.as_deref(),
+        )?;
+        match config.game_mode {
+            GameMode::CaptureTheFlag => state.place_flags()?,
+            GameMode::BattleRoyale => state.start_zone(),
+            _ => {}
+        }
+        let mut game = Self {
+            turn_state: TurnState { turn: 1, state },
+            pending: CallbackInput::default(),
+            turns: Vec::with_capacity(config.max_turn + 1),
+            eliminated: false,
+            stats: config
+                .teams
+                .iter()
+                .map(|&team| (team, Default::default()))
+                .collect(),
+            config,
+        };
+        if !game.is_finished() {
+            game.start_turn();
+        }
+        Ok(game)
+    }
+
+    pub fn config(&self) -> &MatchConfig {
+        &self.config
+    }
+
+    /// The turn that is currently waiting for input, starting at 1
+    pub fn turn(&self) -> usize {
+        self.turn_state.turn
+    }
+
+    pub fn state(&self) -> &State {
+        &self.turn_state.state
+    }
+
+    /// The turns that have been played so far
+    pub fn turns(&self) -> &[CallbackInput] {
+        &self.turns
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.eliminated || self.turn_state.turn > self.config.max_turn
+    }
+
+    pub fn current_input(&self, team: Team) -> ProgramInput<'_> {
+        ProgramInput::new(&self.turn_state, &self.config.teams, team)
+    }
+
+    /// Records `team`'s output for the current turn. Actions for objects that aren't units of
+    /// `team` are ignored, so that teams can't override each other's actions.
+    pub fn submit(&mut self, team: Team, output: ProgramOutput) {
+        let turn_state = &self.turn_state;
+        let objs = &turn_state.state.objs;
+        let turn = &mut self.pending;
+        let stats = self.stats.entry(team).or_default();
+        turn.robot_actions.extend(
+            output
+                .robot_actions
+                .into_iter()
+                .filter(|&(id, _)| is_id_valid(team, id, objs))
+                .map(|(id, action)| {
+                    let action = validate_robot_action(action, team, id, turn_state);
+                    if let Err(RobotErrorAfterValidation::InvalidAction(_)) = action {
+                        stats.invalid_actions += 1;
+                    }
+                    (id, action)
+                }),
+        );
+        turn.logs.insert(team, output.logs);
+        if let Some(order) = output.spawn_order {
+            turn.spawn_orders.insert(team, order);
+        }
+        if self.config.dev_mode {
+            turn.debug_locate_queries
+                .insert(team, output.debug_locate_queries);
+            if output
+                .debug_inspect_tables
+                .keys()
+                .all(|id| is_id_valid(team, *id, objs))
+            {
+                turn.debug_inspect_tables
+                    .extend(output.debug_inspect_tables);
+            }
+        }
+    }
+
+    /// Sets the actions and spawn orders for the current turn to ones that have already been
+    /// validated, e.g. when replaying a recorded match.
+    pub(crate) fn submit_validated(
+        &mut self,
+        actions: BTreeMap<Id, ValidatedRobotAction>,
+        spawn_orders: BTreeMap<Team, Vec<UnitType>>,
+    ) {
+        let objs = &self.turn_state.state.objs;
+        for (id, action) in &actions {
+            // `submit` only takes actions for a team's own units, so they count for that team
+            if let (
+                Err(RobotErrorAfterValidation::InvalidAction(_)),
+                Some(Obj(_, ObjDetails::Unit(unit))),
+            ) = (action, objs.get(id))
+            {
+                self.stats.entry(unit.team).or_default().invalid_actions += 1;
+            }
+        }
+        self.pending.robot_actions = actions;
+        self.pending.spawn_orders = spawn_orders;
+    }
+
+    /// Plays the current turn with the submitted actions and moves on to the next one, returning
+    /// the record of the turn that was just played.
+    ///
+    /// Panics if the match is already finished.
+    pub fn step(&mut self) -> CallbackInput {
+        assert!(!self.is_finished(), "the match is already finished");
+
+        let mut turn = std::mem::take(&mut self.pending);
+        let state = &mut self.turn_state.state;
+        state.spawn_orders.extend(turn.spawn_orders.clone());
+        if self.config.game_mode == GameMode::Hill {
+            for coords in self.config.settings.hill_coords() {
+                let id = match state.grid.get(&coords) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                // units only score while they stay put, including when their team didn't act
+                if let (ObjDetails::Unit(unit), None | Some(Ok(None))) =
+                    (state.objs[id].details(), turn.robot_actions.get(id))
+                {
+                    *state.hill_score.entry(unit.team).or_default() += 1;
+                    self.stats.entry(unit.team).or_default().hill_turns += 1;
+                }
+            }
+        }
+        let events = run_turn(
+            &turn.robot_actions,
+            &mut self.turn_state.state,
+            self.config.game_mode,
+        );
+        turn.move_conflicts = events.move_conflicts;
+        for (team, stats) in events.stats {
+            *self.stats.entry(team).or_default() += stats;
+        }
+        // the new state isn't reflected in `turn` until the next one is started
+        self.turns.push(turn.clone());
+
+        self.turn_state.turn += 1;
+        let last_team_wins = matches!(
+            self.config.game_mode,
+            GameMode::Normal | GameMode::NormalHeal | GameMode::BattleRoyale
+        );
+        let check_elimination = match self.config.settings.end_on_elimination {
+            EndOnElimination::Off => false,
+            EndOnElimination::Immediate => true,
+            EndOnElimination::WhenDecided => !self.spawn_wave_ahead(),
+        };
+        if last_team_wins && check_elimination {
+            let teams = State::create_team_map(&self.turn_state.state.objs, &self.config.teams);
+            self.eliminated = teams.values().filter(|units| !units.is_empty()).count() <= 1;
+        }
+        if !self.is_finished() {
+            self.start_turn();
+        }
+        turn
+    }
+
+    /// Ends the match and determines the winner. The last turn in the output is the final state
+    /// of the board, where every unit passes.
+    pub fn finish(mut self) -> MainOutput {
+        let state = &self.turn_state.state;
+        let final_turn = CallbackInput {
+            state: StateForOutput {
+                objs: state.objs.clone(),
+                turn: self.turn_state.turn,
+            },
+            robot_actions: state.objs.keys().map(|&id| (id, Ok(None))).collect(),
+            hill_score: state.hill_score.clone(),
+            flags: state.flags.clone(),
+            captures: state.captures.clone(),
+            resources: state.resources.clone(),
+            zone: state.zone.clone(),
+            ..Default::default()
+        };
+        self.turns.push(final_turn);
+
+        use GameMode::*;
+        let winner = match self.config.game_mode {
+            Normal | NormalHeal | BattleRoyale => {
+                determine_winner_normal(&self.turns.last().unwrap().state)
+            }
+            Hill => determine_winner_from_scores(&state.hill_score),
+            CaptureTheFlag => determine_winner_from_scores(&state.captures),
+        };
+        MainOutput {
+            winner,
+            errors: BTreeMap::new(),
+            turns: self.turns,
+            settings: self.config.settings,
+            seed: self.config.seed,
+            termination: if self.eliminated {
+                Termination::Elimination
+            } else {
+                Termination::TurnLimit
+            },
+            stats: self.stats,
+        }
+    }
+
+    /// Ends the match because of errors from the programs of one or more teams. The current turn
+    /// is discarded.
+    pub fn finish_with_errors(self, errors: BTreeMap<Team, ProgramError>) -> MainOutput {
+        handle_program_errors(
+            errors,
+            &self.config.teams,
+            self.turns,
+            self.config.settings,
+            self.config.seed,
+            self.stats,
+        )
+    }
+
+    /// Whether a spawn wave that could bring units back is still to come before the match ends
+    fn spawn_wave_ahead(&self) -> bool {
+        let settings = &self.config.settings;
+        match &settings.spawn_settings {
+            Some(spawn_settings)
+                if spawn_settings.spawn_every != 0
+                    && (spawn_settings.recurrent_unit_num != 0 || settings.economy.is_some()) =>
+            {
+                let every = spawn_settings.spawn_every;
+                let next_wave = (self.turn_state.turn - 1).div_ceil(every) * every + 1;
+                next_wave <= self.config.max_turn
+            }
+            _ => false,
+        }
+    }
+
+    /// Spawns units if this is a spawn turn, then takes the snapshot that the turn's record is
+    /// built on.
+    fn start_turn(&mut self) {
+        let TurnState { turn, state } = &mut self.turn_state;
+        // cells are walled off before spawning, so that no units spawn on them
+        state.close_zone(*turn, &mut self.stats);
+        if let Some(spawn_settings) = &self.config.settings.spawn_settings {
+            let teams = &self.config.teams;
+            let for_every_team = |spawned| teams.iter().map(|&team| (team, spawned)).collect();
+            let spawned = if *turn == 1 {
+                for_every_team(state.spawn_units(true))
+            } else if spawn_settings.spawn_every != 0
+                && (*turn - 1) % spawn_settings.spawn_every == 0
+            {
+                for team in state.clear_spawn() {
+                    self.stats.entry(team).or_default().units_lost += 1;
+                }
+                if self.config.settings.economy.is_some() {
+                    state.buy_units()
+                } else {
+                    for_every_team(state.spawn_units(false))
+                }
+            } else {
+                BTreeMap::new()
+            };
+            for (team, spawned) in spawned {
+                self.stats.entry(team).or_default().units_spawned += spawned;
+            }
+        }
+
+        let visible_units = match &self.config.settings.fog_of_war {
+            Some(fog) => self
+                .config
+                .teams
+                .iter()
+                .map(|&team| (team, state.visible_units(team, fog)))
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        self.pending = CallbackInput {
+            state: StateForOutput {
+                objs: state.objs.clone(),
+                turn: *turn,
+            },
+            visible_units,
+            hill_score: state.hill_score.clone(),
+            flags: state.flags.clone(),
+            captures: state.captures.clone(),
+            resources: state.resources.clone(),
+            zone: state.zone.clone(),
+            ..Default::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Action, ActionResult, ActionType, Coords, Direction, InitObj, SpawnSettings, Terrain,
+        TerrainType, Unit, UnitType,
+    };
+
+    fn config(teams: Vec<Team>, settings: Settings) -> MatchConfig {
+        MatchConfig {
+            teams,
+            max_turn: 25,
+            dev_mode: false,
+            settings,
+            game_mode: GameMode::Normal,
+            seed: Some("game".to_owned()),
+        }
+    }
+
+    /// Steps through the match without any actions, returning the number of turns played
+    fn play_idle(game: &mut Game) -> usize {
+        let mut turns = 0;
+        while !game.is_finished() {
+            game.step();
+            turns += 1;
+        }
+        turns
+    }
+
+    #[test]
+    fn matches_with_the_same_seed_get_the_same_ids() {
+        let teams = vec![Team::Red, Team::Blue];
+        let mut games = [
+            Game::new(config(teams.clone(), Settings::default())).unwrap(),
+            Game::new(config(teams, Settings::default())).unwrap(),
+        ];
+        // stepped in lockstep, so that a shared counter would hand them different ids
+        while !games[0].is_finished() {
+            for game in &mut games {
+                game.step();
+            }
+            assert_eq!(games[0].state().objs, games[1].state().objs);
+        }
+        assert!(games[1].is_finished());
+    }
+
+    #[test]
+    fn elimination_waits_for_the_last_spawn_wave() {
+        let settings = Settings {
+            end_on_elimination: EndOnElimination::WhenDecided,
+            ..Default::default()
+        };
+        // waves come on turns 11 and 21, so with 1 team the match ends once 21 is played
+        let mut game = Game::new(config(vec![Team::Red], settings.clone())).unwrap();
+        assert_eq!(play_idle(&mut game), 21);
+        assert_eq!(game.finish().termination, Termination::Elimination);
+
+        let settings = Settings {
+            spawn_settings: Some(SpawnSettings {
+                recurrent_unit_num: 0,
+                ..Default::default()
+            }),
+            ..settings
+        };
+        let mut game = Game::new(config(vec![Team::Red], settings.clone())).unwrap();
+        assert_eq!(play_idle(&mut game), 1);
+
+        // with 2 teams, the match goes on until one of them is wiped out
+        let mut game = Game::new(config(vec![Team::Red, Team::Blue], settings)).unwrap();
+        game.step();
+        assert!(!game.is_finished());
+        let state = &mut game.turn_state.state;
+        let blue_units = State::create_team_map(&state.objs, &[Team::Blue])[&Team::Blue].clone();
+        for id in blue_units {
+            let obj = state.objs.remove(&id).unwrap();
+            state.grid.remove(&obj.coords());
+        }
+        game.step();
+        assert!(game.is_finished());
+        let output = game.finish();
+        assert_eq!(output.winner, Some(Team::Red));
+        assert_eq!(output.termination, Termination::Elimination);
+    }
+
+    #[test]
+    fn elimination_can_end_matches_between_spawn_waves() {
+        let teams = vec![Team::Red, Team::Blue];
+        let wipe_out_blue = |end_on_elimination| {
+            let settings = Settings {
+                end_on_elimination,
+                ..Default::default()
+            };
+            let mut game = Game::new(config(teams.clone(), settings)).unwrap();
+            game.step();
+            let state = &mut game.turn_state.state;
+            let blue_units =
+                State::create_team_map(&state.objs, &[Team::Blue])[&Team::Blue].clone();
+            for id in blue_units {
+                let obj = state.objs.remove(&id).unwrap();
+                state.grid.remove(&obj.coords());
+            }
+            game.step();
+            game
+        };
+
+        // blue could still come back in the wave on turn 11
+        assert!(!wipe_out_blue(EndOnElimination::WhenDecided).is_finished());
+        assert!(!wipe_out_blue(EndOnElimination::Off).is_finished());
+        let game = wipe_out_blue(EndOnElimination::Immediate);
+        assert!(game.is_finished());
+        let output = game.finish();
+        assert_eq!(output.turns.len(), 3);
+        assert_eq!(output.winner, Some(Team::Red));
+        assert_eq!(output.termination, Termination::Elimination);
+    }
+
+    /// Submits `team`'s actions for the units at the given coordinates
+    fn act(game: &mut Game, team: Team, actions: &[(Coords, ActionType, Direction)]) {
+        let robot_actions = actions
+            .iter()
+            .map(|&(coords, type_, direction)| {
+                let id = game.state().grid[&coords];
+                (id, Ok(Some(Action { type_, direction })))
+            })
+            .collect();
+        submit_actions(game, team, robot_actions);
+    }
+
+    fn submit_actions(game: &mut Game, team: Team, robot_actions: BTreeMap<Id, ActionResult>) {
+        let output = ProgramOutput {
+            robot_actions,
+            logs: Vec::new(),
+            debug_inspect_tables: BTreeMap::new(),
+            debug_locate_queries: Vec::new(),
+            spawn_order: None,
+        };
+        game.submit(team, output);
+    }
+
+    #[test]
+    fn stats_are_recorded() {
+        use {ActionType::*, Direction::*};
+        let (red, blue) = (Team::Red, Team::Blue);
+        let unit = |team, health| {
+            ObjDetails::Unit(Unit {
+                type_: UnitType::Soldier,
+                team,
+                health,
+            })
+        };
+        let fountain = ObjDetails::Terrain(Terrain {
+            type_: TerrainType::Fountain,
+            health: None,
+        });
+        let settings = Settings {
+            spawn_settings: None,
+            grid_init: vec![
+                InitObj(Coords(8, 9), unit(red, 4)),
+                InitObj(Coords(8, 9), fountain),
+                InitObj(Coords(9, 9), unit(blue, 5)),
+                InitObj(Coords(10, 9), unit(blue, 5)),
+            ],
+            ..Default::default()
+        };
+        let mut game = Game::new(config(vec![red, blue], settings)).unwrap();
+
+        // blue hits its own unit, has the other one harvest without an economy, and tries to
+        // move red's, which is ignored
+        act(&mut game, red, &[(Coords(8, 9), Attack, East)]);
+        act(
+            &mut game,
+            blue,
+            &[
+                (Coords(10, 9), Attack, West),
+                (Coords(9, 9), Harvest, North),
+                (Coords(8, 9), Move, West),
+            ],
+        );
+        game.step();
+        for _ in 0..3 {
+            act(&mut game, red, &[(Coords(8, 9), Attack, East)]);
+            game.step();
+        }
+        assert!(!game.state().grid.contains_key(&Coords(9, 9)));
+
+        let stats = game.finish().stats;
+        let expected_red = TeamStats {
+            kills: 1,
+            damage_dealt: 4,
+            healing_done: 1,
+            ..Default::default()
+        };
+        let expected_blue = TeamStats {
+            units_lost: 1,
+            damage_received: 5,
+            invalid_actions: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats[&red], expected_red);
+        assert_eq!(stats[&blue], expected_blue);
+    }
+
+    /// A red and a blue soldier facing each other across the middle of the map
+    fn duel() -> Game {
+        let unit = |team| {
+            ObjDetails::Unit(Unit {
+                type_: UnitType::Soldier,
+                team,
+                health: 5,
+            })
+        };
+        let settings = Settings {
+            spawn_settings: None,
+            grid_init: vec![
+                InitObj(Coords(5, 9), unit(Team::Red)),
+                InitObj(Coords(13, 9), unit(Team::Blue)),
+            ],
+            ..Default::default()
+        };
+        Game::new(config(vec![Team::Red, Team::Blue], settings)).unwrap()
+    }
+
+    #[test]
+    fn matches_are_played_turn_by_turn() {
+        let mut game = duel();
+        assert_eq!(game.turn(), 1);
+        assert!(game.turns().is_empty());
+        let input = game.current_input(Team::Red);
+        assert_eq!(input.team, Team::Red);
+        assert_eq!(input.state.turn, 1);
+        let red = input.state.teams[&Team::Red][0];
+
+        for turn in 1..=3 {
+            act(
+                &mut game,
+                Team::Red,
+                &[(Coords(4 + turn, 9), ActionType::Move, Direction::East)],
+            );
+            let logs = vec![format!("turn {}", turn)];
+            game.submit(
+                Team::Blue,
+                ProgramOutput {
+                    robot_actions: BTreeMap::new(),
+                    logs: logs.clone(),
+                    debug_inspect_tables: BTreeMap::new(),
+                    debug_locate_queries: Vec::new(),
+                    spawn_order: None,
+                },
+            );
+            let played = game.step();
+            assert_eq!(played.state.turn, turn);
+            assert!(matches!(played.robot_actions[&red], Ok(Some(_))));
+            assert_eq!(played.logs[&Team::Blue], logs);
+        }
+        assert_eq!(game.turn(), 4);
+        assert_eq!(game.turns().len(), 3);
+        assert_eq!(game.state().objs[&red].coords(), Coords(8, 9));
+
+        assert_eq!(play_idle(&mut game), 22);
+        let output = game.finish();
+        // the final state is recorded as an extra turn
+        assert_eq!(output.turns.len(), 26);
+        assert_eq!(output.winner, None);
+        assert_eq!(output.termination, Termination::TurnLimit);
+        assert_eq!(output.seed.as_deref(), Some("game"));
+    }
+
+    #[test]
+    fn errors_end_the_match() {
+        let mut game = duel();
+        game.step();
+        game.step();
+        let errors = vec![(Team::Blue, ProgramError::NoData)]
+            .into_iter()
+            .collect();
+        let output = game.finish_with_errors(errors);
+        assert_eq!(output.winner, Some(Team::Red));
+        assert_eq!(output.termination, Termination::Error);
+        assert_eq!(output.turns.len(), 2);
+        assert!(matches!(output.errors[&Team::Blue], ProgramError::NoData));
+        assert_eq!(output.seed.as_deref(), Some("game"));
+
+        // nobody wins if every team errored
+        let errors = vec![
+            (Team::Red, ProgramError::InternalError),
+            (Team::Blue, ProgramError::NoData),
+        ];
+        let output = duel().finish_with_errors(errors.into_iter().collect());
+        assert_eq!(output.winner, None);
+        assert!(output.turns.is_empty());
+    }
+
+    #[test]
+    fn hill_score_matches_the_recorded_turns() {
+        use Direction::*;
+        let (red, blue) = (Team::Red, Team::Blue);
+        let unit = |team| {
+            ObjDetails::Unit(Unit {
+                type_: UnitType::Soldier,
+                team,
+                health: 5,
+            })
+        };
+        let settings = Settings {
+            spawn_settings: None,
+            grid_init: vec![
+                InitObj(Coords(9, 9), unit(red)),
+                InitObj(Coords(11, 9), unit(red)),
+                InitObj(Coords(8, 8), unit(blue)),
+            ],
+            ..Default::default()
+        };
+        let config = MatchConfig {
+            game_mode: GameMode::Hill,
+            ..config(vec![red, blue], settings)
+        };
+        let mut game = Game::new(config).unwrap();
+        let id_at = |game: &Game, coords| game.state().grid[&coords];
+        let (stayer, mover, blue_unit) = (
+            id_at(&game, Coords(9, 9)),
+            id_at(&game, Coords(11, 9)),
+            id_at(&game, Coords(8, 8)),
+        );
+
+        // red keeps one unit on the hill, and moves the other on and off it, which doesn't score.
+        // blue's unit only scores on the turns that it doesn't attack, where blue submits nothing.
+        while !game.is_finished() {
+            let turn = game.turn();
+            let step = if turn % 2 == 1 { West } else { East };
+            let act = |type_, direction| Ok(Some(Action { type_, direction }));
+            let red_actions = vec![(stayer, Ok(None)), (mover, act(ActionType::Move, step))];
+            submit_actions(&mut game, red, red_actions.into_iter().collect());
+            if step == East {
+                let blue_actions = vec![(blue_unit, act(ActionType::Attack, North))];
+                submit_actions(&mut game, blue, blue_actions.into_iter().collect());
+            }
+            game.step();
+        }
+        let output = game.finish();
+
+        // count the way scores used to be counted, from each turn's units and actions, skipping
+        // the final turn where every unit passes. Units without an action stay put too.
+        let hill = Settings::default().hill_coords();
+        let (final_turn, played) = output.turns.split_last().unwrap();
+        let mut counted = BTreeMap::new();
+        for turn in played {
+            for (id, obj) in turn.state.objs.iter() {
+                if let (ObjDetails::Unit(unit), None | Some(Ok(None))) =
+                    (obj.details(), turn.robot_actions.get(id))
+                {
+                    if hill.contains(&obj.coords()) {
+                        *counted.entry(unit.team).or_default() += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(final_turn.hill_score, counted);
+        // the final turn would have scored for red, had it been counted
+        assert_eq!(final_turn.state.objs[&stayer].coords(), Coords(9, 9));
+        assert!(matches!(final_turn.robot_actions[&stayer], Ok(None)));
+        assert_eq!(counted[&red], 25);
+        assert_eq!(counted[&blue], 13);
+        assert_eq!(output.winner, Some(red));
+    }
+}
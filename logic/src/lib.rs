@@ -1,72 +1,47 @@
 use futures_util::{stream, FutureExt, StreamExt};
+use maybe_owned::MaybeOwned;
 use multimap::MultiMap;
-use rand::rngs::StdRng;
-use rand::seq::SliceRandom;
-use rand::SeedableRng;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::{BTreeMap, HashMap};
-use std::hash::{Hash, Hasher};
-use std::sync::atomic;
+use rand::{RngCore, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
+pub use game::{Game, MatchConfig};
 pub use types::*;
 
+mod game;
+pub mod map;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod replay;
 mod types;
 
-#[inline]
-fn binary_remove<T: Ord>(v: &mut Vec<T>, el: &T) {
-    let idx = v.binary_search(el).expect("element to remove not in vec");
-    v.remove(idx);
-}
-
-static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(1);
-
-pub fn reset_id() {
-    COUNTER.store(1, atomic::Ordering::Relaxed);
-}
-
-pub fn new_id() -> Id {
-    Id(COUNTER.fetch_add(1, atomic::Ordering::Relaxed))
-}
-
-fn init_obj_to_obj(InitObj(coords, details): InitObj) -> Obj {
-    let basic_obj = BasicObj {
-        id: new_id(),
-        coords,
-    };
+fn init_obj_to_obj(id: Id, InitObj(coords, details): InitObj) -> Obj {
+    let basic_obj = BasicObj { id, coords };
 
     Obj(basic_obj, details)
 }
 
 impl Obj {
-    const UNIT_HEALTH: usize = 5;
-    const ATTACK_POWER: usize = 1;
-    const HEAL_POWER: usize = 1;
-
-    pub fn new_terrain(type_: TerrainType, coords: Coords) -> Self {
+    pub fn new_terrain(id: Id, type_: TerrainType, coords: Coords) -> Self {
         Self(
-            Self::new_basic_obj(coords),
-            ObjDetails::Terrain(Terrain { type_ }),
+            BasicObj { id, coords },
+            ObjDetails::Terrain(Terrain {
+                type_,
+                health: None,
+            }),
         )
     }
 
-    pub fn new_unit(type_: UnitType, coords: Coords, team: Team) -> Self {
+    pub fn new_unit(id: Id, type_: UnitType, coords: Coords, team: Team, health: usize) -> Self {
         Self(
-            Self::new_basic_obj(coords),
+            BasicObj { id, coords },
             ObjDetails::Unit(Unit {
                 type_,
                 team,
-                health: Self::UNIT_HEALTH,
+                health,
             }),
         )
     }
 
-    fn new_basic_obj(coords: Coords) -> BasicObj {
-        BasicObj {
-            coords,
-            id: new_id(),
-        }
-    }
-
     pub fn id(&self) -> Id {
         self.0.id
     }
@@ -78,53 +53,241 @@ impl Obj {
     }
 }
 
+impl TerrainType {
+    const FOUNTAIN_HEAL: usize = 1;
+    const LAVA_DAMAGE: usize = 1;
+    /// How much less damage a unit in cover takes from all the attacks on it in a turn
+    const COVER_PROTECTION: usize = 1;
+
+    /// Whether units are kept out of cells with this terrain. Only blocking terrain is part of
+    /// `State.grid`, the rest is in `State.terrain_grid`.
+    pub fn is_blocking(self) -> bool {
+        matches!(self, TerrainType::Wall | TerrainType::Resource)
+    }
+}
+
+impl UnitType {
+    pub const ALL: [UnitType; 4] = [
+        UnitType::Soldier,
+        UnitType::Archer,
+        UnitType::Tank,
+        UnitType::Medic,
+    ];
+
+    /// The stats that `Settings` uses unless they're overridden
+    pub fn default_stats(self) -> UnitStats {
+        use ActionType::*;
+        match self {
+            UnitType::Soldier => UnitStats {
+                health: 5,
+                max_health: 5,
+                attack_power: 1,
+                attack_range: 1,
+                heal_power: 1,
+                move_every: 1,
+                actions: vec![Move, Attack, Heal, Harvest],
+                cost: 5,
+            },
+            UnitType::Archer => UnitStats {
+                health: 3,
+                max_health: 3,
+                attack_power: 1,
+                attack_range: 3,
+                heal_power: 0,
+                move_every: 1,
+                actions: vec![Move, Attack, Harvest],
+                cost: 6,
+            },
+            UnitType::Tank => UnitStats {
+                health: 10,
+                max_health: 10,
+                attack_power: 2,
+                attack_range: 1,
+                heal_power: 0,
+                move_every: 2,
+                actions: vec![Move, Attack, Harvest],
+                cost: 10,
+            },
+            UnitType::Medic => UnitStats {
+                health: 4,
+                max_health: 4,
+                attack_power: 0,
+                attack_range: 0,
+                heal_power: 2,
+                move_every: 1,
+                actions: vec![Move, Heal, Harvest],
+                cost: 6,
+            },
+        }
+    }
+}
+
+/// Hashes `seed_str` with 64-bit FNV-1a, then expands the hash into a 32-byte seed with SplitMix64.
+/// Both algorithms are fully specified here and everything is little-endian, so the seed (and
+/// with it the whole match) is the same on every platform and compiler version.
 fn string_to_seed(seed_str: &str) -> [u8; 32] {
-    let mut hasher = DefaultHasher::new();
-    seed_str.hash(&mut hasher);
-    let result = hasher.finish().to_ne_bytes();
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = FNV_OFFSET_BASIS;
+    for &byte in seed_str.as_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
 
-    // Convert the hash result into a fixed-size seed
     let mut seed = [0; 32];
-    for i in 0..4 {
-        seed[i * 8..(i + 1) * 8].copy_from_slice(&result);
+    for chunk in seed.chunks_exact_mut(8) {
+        hash = hash.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = hash;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_le_bytes());
     }
     seed
 }
 
+/// Picks a random element of `slice`. This is used instead of `SliceRandom::choose`, whose
+/// sampling algorithm isn't guaranteed to stay the same between `rand` versions.
+fn choose<'a, T>(slice: &'a [T], rng: &mut GameRng) -> Option<&'a T> {
+    // multiply-shift: maps a random u32 onto 0..len with negligible bias for small slices
+    let idx = (rng.next_u32() as u64 * slice.len() as u64) >> 32;
+    slice.get(idx as usize)
+}
+
+impl Settings {
+    pub fn validate(&self, team_count: usize) -> Result<(), SettingsError> {
+        if !(1..=MAX_TEAMS).contains(&team_count) {
+            return Err(SettingsError::InvalidTeamCount(team_count));
+        }
+        let size = self.grid_size;
+        if !(MIN_GRID_SIZE..=MAX_GRID_SIZE).contains(&size) || size % 2 != 1 {
+            return Err(SettingsError::InvalidGridSize(size));
+        }
+        let in_bounds = |&Coords(x, y): &Coords| x < size && y < size;
+        let custom_coords = self.grid_init.iter().map(|InitObj(coords, _)| coords);
+        let custom_coords = custom_coords
+            .chain(self.spawn_points.iter().flatten())
+            .chain(self.hill.iter().flatten())
+            .chain(&self.flag_base);
+        if let Some(coords) = custom_coords.clone().find(|coords| !in_bounds(coords)) {
+            return Err(SettingsError::OutOfBounds(*coords));
+        }
+        if let Some(spawn_settings) = &self.spawn_settings {
+            if spawn_settings.unit_types.is_empty() {
+                return Err(SettingsError::NoUnitTypes);
+            }
+        } else if self.economy.is_some() {
+            return Err(SettingsError::EconomyWithoutSpawns);
+        }
+        if let Some((&type_, _)) = self
+            .unit_stats
+            .iter()
+            .find(|(_, stats)| stats.health == 0 || stats.health > stats.max_health)
+        {
+            return Err(SettingsError::InvalidUnitStats(type_));
+        }
+        if let Some((&type_, _)) = self
+            .wall_health
+            .iter()
+            .find(|(type_, &health)| !type_.is_blocking() || health == 0)
+        {
+            return Err(SettingsError::InvalidWallHealth(type_));
+        }
+        if let Some(spawn_points) = &self.spawn_points {
+            if let Some(coords) = spawn_points.iter().find(|&&loc| {
+                State::symmetric_locs(loc, team_count, size)
+                    .iter()
+                    .any(|rotated| !spawn_points.contains(rotated))
+            }) {
+                return Err(SettingsError::AsymmetricSpawn(*coords));
+            }
+        }
+        Ok(())
+    }
+
+    /// The base of the first team in `GameMode::CaptureTheFlag`
+    pub fn flag_base(&self) -> Coords {
+        self.flag_base.unwrap_or(Coords(2, self.grid_size / 2))
+    }
+
+    pub fn hill_coords(&self) -> Vec<Coords> {
+        match &self.hill {
+            Some(hill) => hill.clone(),
+            None => default_hill_coords(self.grid_size).collect(),
+        }
+    }
+}
+
 impl State {
     pub fn new(
-        grid_type: MapType,
-        grid_size: usize,
         settings: Settings,
+        teams: &[Team],
         seed: Option<&str>,
-    ) -> Self {
+    ) -> Result<Self, SettingsError> {
+        settings.validate(teams.len())?;
+        let grid_size = settings.grid_size;
+
+        let mut ids = IdAllocator::default();
+
         // create initial objs/map combination
-        let (mut objs, spawn_points) = Self::init(grid_type, grid_size);
-        let mut grid = Self::create_grid_map(&objs);
+        let (mut objs, mut spawn_points) = Self::init(settings.map_type, grid_size, &mut ids);
+        if let Some(custom_spawn_points) = &settings.spawn_points {
+            spawn_points = custom_spawn_points.clone();
+            spawn_points.sort();
+            spawn_points.dedup();
+        }
+        let mut grid = Self::create_grid_map(&objs).with_size(grid_size);
+        let mut terrain_grid = GridMap::new(grid_size);
 
         let it = settings
             .grid_init
             .clone()
             .into_iter()
-            .map(init_obj_to_obj)
+            .map(|init_obj| init_obj_to_obj(ids.new_id(), init_obj))
             .inspect(|obj| {
-                grid.insert(obj.coords(), obj.id());
+                let layer = match obj.details() {
+                    ObjDetails::Terrain(terrain) if !terrain.type_.is_blocking() => {
+                        &mut terrain_grid
+                    }
+                    _ => &mut grid,
+                };
+                layer.insert(obj.coords(), obj.id());
             });
         objs.extend(it.map(|obj| (obj.id(), obj)));
+        for obj in objs.values_mut() {
+            if let Obj(_, ObjDetails::Terrain(terrain)) = obj {
+                if let Some(&health) = settings.wall_health.get(&terrain.type_) {
+                    terrain.health = Some(health);
+                }
+            }
+        }
 
-        Self {
+        let resources = settings
+            .economy
+            .as_ref()
+            .map_or(0, |economy| economy.starting_resources);
+        Ok(Self {
             objs,
             grid,
+            terrain_grid,
             spawn_points,
-            settings,
+            teams: teams.to_vec(),
+            ids,
             rng: match seed {
-                Some(s) => StdRng::from_seed(string_to_seed(s)),
+                Some(s) => GameRng::from_seed(string_to_seed(s)),
                 None => types::init_rng(),
             },
-        }
+            hill_score: teams.iter().map(|&team| (team, 0)).collect(),
+            flags: BTreeMap::new(),
+            captures: teams.iter().map(|&team| (team, 0)).collect(),
+            resources: teams.iter().map(|&team| (team, resources)).collect(),
+            spawn_orders: BTreeMap::new(),
+            zone: None,
+            settings,
+        })
     }
 
-    fn init(type_: MapType, size: usize) -> (ObjMap, Vec<Coords>) {
+    fn init(type_: MapType, size: usize, ids: &mut IdAllocator) -> (ObjMap, Vec<Coords>) {
         let distance_from_center = |Coords(x, y)| {
             ((size / 2) as i32 - x as i32).pow(2) + ((size / 2) as i32 - y as i32).pow(2)
         };
@@ -137,9 +300,10 @@ impl State {
                     coords.0 == 0 || coords.0 == size - 1 || coords.1 == 0 || coords.1 == size - 1
                 }
                 MapType::Circle => distance_from_center(coords) >= (size / 2).pow(2) as i32,
+                MapType::Custom => false,
             })
             .map(|coords| {
-                let obj = Obj::new_terrain(TerrainType::Wall, coords);
+                let obj = Obj::new_terrain(ids.new_id(), TerrainType::Wall, coords);
                 (obj.id(), obj)
             })
             .collect();
@@ -157,6 +321,7 @@ impl State {
                                 || Coords(x, y.saturating_sub(1)) == obj.coords()
                         })
                 }
+                MapType::Custom => false,
             })
             .collect();
 
@@ -167,42 +332,365 @@ impl State {
         objs.values().map(|obj| (obj.coords(), obj.id())).collect()
     }
 
+    /// The type of the non-blocking terrain at `coords`, if there is any
+    pub fn terrain_at(&self, coords: Coords) -> Option<TerrainType> {
+        let id = self.terrain_grid.get(&coords)?;
+        match self.objs.get(id)?.details() {
+            ObjDetails::Terrain(terrain) => Some(terrain.type_),
+            _ => None,
+        }
+    }
+
+    /// The units that `team` can see under `fog`: its own units, and the other units within the
+    /// vision radius of at least one of them. Terrain is always visible, so it isn't included.
+    pub fn visible_units(&self, team: Team, fog: &FogOfWar) -> Vec<Id> {
+        let viewers = self
+            .objs
+            .values()
+            .filter(|obj| matches!(obj.details(), ObjDetails::Unit(unit) if unit.team == team))
+            .map(|obj| obj.coords())
+            .collect::<Vec<_>>();
+        self.objs
+            .values()
+            .filter(|obj| match obj.details() {
+                ObjDetails::Unit(unit) => {
+                    unit.team == team
+                        || viewers
+                            .iter()
+                            .any(|&from| self.can_see(from, obj.coords(), fog))
+                }
+                ObjDetails::Terrain(_) => false,
+            })
+            .map(|obj| obj.id())
+            .collect()
+    }
+
+    fn can_see(&self, from: Coords, to: Coords, fog: &FogOfWar) -> bool {
+        let (dx, dy) = (to.0 as i64 - from.0 as i64, to.1 as i64 - from.1 as i64);
+        let radius = fog.vision_radius as i64;
+        if dx * dx + dy * dy > radius * radius {
+            return false;
+        }
+        if !fog.walls_block {
+            return true;
+        }
+        // check the cells that a straight line between the two cell centers passes closest to
+        let steps = dx.abs().max(dy.abs());
+        (1..steps).all(|step| {
+            let along = |start: usize, delta: i64| {
+                (start as f64 + (delta * step) as f64 / steps as f64).round() as usize
+            };
+            let coords = Coords(along(from.0, dx), along(from.1, dy));
+            !matches!(
+                self.grid.get(&coords).map(|id| self.objs[id].details()),
+                Some(ObjDetails::Terrain(terrain)) if terrain.type_ == TerrainType::Wall
+            )
+        })
+    }
+
+    /// Checks whether `unit`, standing at `coords`, can take `action` on `turn`
+    pub fn check_unit_action(
+        &self,
+        unit: &Unit,
+        coords: Coords,
+        action: Action,
+        turn: usize,
+    ) -> Result<(), &'static str> {
+        let Action { type_, direction } = action;
+        if direction.is_diagonal() && self.settings.directions == DirectionSet::Four {
+            return Err("Diagonal directions aren't enabled in this match");
+        }
+        let stats = &self.settings.unit_stats[unit.type_];
+        if !stats.actions.contains(&type_) {
+            return Err("Unit type can't take this action");
+        }
+        if type_ == ActionType::Harvest && self.settings.economy.is_none() {
+            return Err("Harvesting isn't enabled in this match");
+        }
+        let mut move_every = stats.move_every.max(1);
+        if self.terrain_at(coords) == Some(TerrainType::Swamp) {
+            move_every *= 2;
+        }
+        if type_ == ActionType::Move && !(turn - 1).is_multiple_of(move_every) {
+            return Err("Unit can't move on this turn");
+        }
+        Ok(())
+    }
+
+    /// Rotates `loc` clockwise around the center of the grid, 90 degrees per quarter turn
     #[inline]
-    fn mirror_loc(loc: Coords) -> Coords {
-        Coords(GRID_SIZE - loc.0 - 1, GRID_SIZE - loc.1 - 1)
+    fn rotate_loc(loc: Coords, quarter_turns: usize, grid_size: usize) -> Coords {
+        (0..quarter_turns % 4).fold(loc, |Coords(x, y), _| Coords(grid_size - y - 1, x))
+    }
+
+    /// `loc` followed by its equivalent location for every other team. 2 teams are placed with
+    /// point symmetry, and 4 teams with 4-fold rotational symmetry. A square grid can't be split 3
+    /// ways, so 3 teams are laid out like 4: they take the first 3 quarter turns, clockwise, and
+    /// the quarter of the grid that the 4th would get stays empty.
+    pub(crate) fn symmetric_locs(loc: Coords, team_count: usize, grid_size: usize) -> Vec<Coords> {
+        let order = match team_count {
+            0 | 1 => 1,
+            2 => 2,
+            _ => 4,
+        };
+        (0..order)
+            .map(|i| Self::rotate_loc(loc, i * 4 / order, grid_size))
+            .collect()
     }
 
-    fn clear_spawn(&mut self) {
+    /// Puts each team's flag at its base, for `GameMode::CaptureTheFlag`. Fails if teams would
+    /// share a base, e.g. one in the center of the grid, or if a base is on blocking terrain.
+    pub(crate) fn place_flags(&mut self) -> Result<(), SettingsError> {
+        let bases = Self::symmetric_locs(
+            self.settings.flag_base(),
+            self.teams.len(),
+            self.settings.grid_size,
+        );
+        for (i, base) in bases.iter().enumerate() {
+            let blocked = self.grid.get(base).is_some_and(|id| {
+                matches!(
+                    self.objs[id].details(),
+                    ObjDetails::Terrain(terrain) if terrain.type_.is_blocking()
+                )
+            });
+            if blocked || bases[..i].contains(base) {
+                return Err(SettingsError::InvalidFlagBase(*base));
+            }
+        }
+        self.flags = self
+            .teams
+            .iter()
+            .zip(bases)
+            .map(|(&team, base)| {
+                let flag = Flag {
+                    base,
+                    coords: base,
+                    carrier: None,
+                };
+                (team, flag)
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Moves the flags along with the units carrying them, so that a flag is always where its
+    /// carrier is when the carrier dies
+    fn carry_flags(&mut self) {
+        let Self { objs, flags, .. } = self;
+        for flag in flags.values_mut() {
+            if let Some(carrier) = flag.carrier.and_then(|id| objs.get(&id)) {
+                flag.coords = carrier.coords();
+            }
+        }
+    }
+
+    /// Drops the flag that unit `id` is carrying, if any, for when the unit is removed
+    fn drop_flags(flags: &mut BTreeMap<Team, Flag>, id: Id) {
+        for flag in flags.values_mut().filter(|flag| flag.carrier == Some(id)) {
+            flag.carrier = None;
+        }
+    }
+
+    /// Moves the flags along with the units carrying them, and has units pick up, return and
+    /// capture flags
+    fn update_flags(&mut self) {
+        let Self {
+            objs,
+            grid,
+            flags,
+            captures,
+            ..
+        } = self;
+        let team_of = |id: &Id| match objs.get(id).map(|obj| obj.details()) {
+            Some(ObjDetails::Unit(unit)) => Some(unit.team),
+            _ => None,
+        };
+        let bases = flags
+            .iter()
+            .map(|(&team, flag)| (team, flag.base))
+            .collect::<BTreeMap<_, _>>();
+        for (&team, flag) in flags.iter_mut() {
+            match flag.carrier.map(|id| objs.get(&id)) {
+                Some(Some(carrier)) => flag.coords = carrier.coords(),
+                // the carrier died, so the flag drops where it died
+                Some(None) => flag.carrier = None,
+                None => {}
+            }
+            if flag.carrier.is_none() {
+                if let Some(&id) = grid.get(&flag.coords) {
+                    match team_of(&id) {
+                        Some(unit_team) if unit_team != team => flag.carrier = Some(id),
+                        // a unit that reaches its own team's flag returns it to the base
+                        Some(_) => flag.coords = flag.base,
+                        None => {}
+                    }
+                }
+            }
+            let capturer = flag.carrier.as_ref().and_then(team_of);
+            if let Some(capturer) = capturer.filter(|team| bases[team] == flag.coords) {
+                *captures.entry(capturer).or_default() += 1;
+                flag.carrier = None;
+                flag.coords = flag.base;
+            }
+        }
+    }
+
+    /// The ring of the grid that `coords` is in, i.e. its distance from the nearest edge
+    pub fn ring(coords: Coords, grid_size: usize) -> usize {
+        let Coords(x, y) = coords;
+        x.min(y).min(grid_size - 1 - x).min(grid_size - 1 - y)
+    }
+
+    /// Starts the schedule of closing walls, for `GameMode::BattleRoyale`
+    pub(crate) fn start_zone(&mut self) {
+        let shrink = &self.settings.shrink;
+        let rings = self.settings.grid_size.saturating_sub(shrink.min_size) / 2;
+        self.zone = Some(Zone {
+            closed_rings: 0,
+            upcoming: (0..rings)
+                .map(|ring| shrink.start_turn + ring * shrink.shrink_every)
+                .collect(),
+        });
+    }
+
+    /// Closes the rings that are scheduled to close by `turn`, and walls off the cells of closed
+    /// rings. Units standing on them die, and anything else on them is replaced. With
+    /// `ShrinkSettings::damage`, units on the next ring to close get hurt too.
+    fn close_zone(&mut self, turn: usize, stats: &mut BTreeMap<Team, TeamStats>) {
+        let Self {
+            objs,
+            grid,
+            terrain_grid,
+            settings,
+            ids,
+            zone,
+            flags,
+            ..
+        } = self;
+        let zone = match zone {
+            Some(zone) => zone,
+            None => return,
+        };
+        while zone.upcoming.first().is_some_and(|&closes| closes <= turn) {
+            zone.upcoming.remove(0);
+            zone.closed_rings += 1;
+        }
+        let size = settings.grid_size;
+        let cells = (0..size).flat_map(|x| (0..size).map(move |y| Coords(x, y)));
+
+        // the next ring only hurts once the walls have started closing in
+        let started = zone.closed_rings > 0 && !zone.upcoming.is_empty();
+        if let Some(damage) = settings.shrink.damage.filter(|_| started) {
+            let closing = cells
+                .clone()
+                .filter(|&coords| Self::ring(coords, size) == zone.closed_rings);
+            for coords in closing {
+                let id = match grid.get(&coords) {
+                    Some(&id) => id,
+                    None => continue,
+                };
+                if let Some(Obj(_, ObjDetails::Unit(unit))) = objs.get_mut(&id) {
+                    let damage = damage.min(unit.health);
+                    unit.health -= damage;
+                    let stats = stats.entry(unit.team).or_default();
+                    stats.damage_received += damage;
+                    if unit.health == 0 {
+                        stats.units_lost += 1;
+                        Self::drop_flags(flags, id);
+                        objs.remove(&id).unwrap();
+                        grid.remove(&coords).unwrap();
+                    }
+                }
+            }
+        }
+
+        let closed = cells.filter(|&coords| Self::ring(coords, size) < zone.closed_rings);
+        for coords in closed {
+            if let Some(&id) = grid.get(&coords) {
+                match objs[&id].details() {
+                    // already walled off, or as good as
+                    ObjDetails::Terrain(Terrain {
+                        type_: TerrainType::Wall,
+                        health: None,
+                    }) => continue,
+                    ObjDetails::Unit(unit) => {
+                        let stats = stats.entry(unit.team).or_default();
+                        stats.damage_received += unit.health;
+                        stats.units_lost += 1;
+                        Self::drop_flags(flags, id);
+                    }
+                    // destructible walls and resource nodes would let units break through
+                    ObjDetails::Terrain(_) => {}
+                }
+                objs.remove(&id).unwrap();
+                grid.remove(&coords).unwrap();
+            }
+            if let Some(terrain_id) = terrain_grid.remove(&coords) {
+                objs.remove(&terrain_id);
+            }
+            let wall = Obj::new_terrain(ids.new_id(), TerrainType::Wall, coords);
+            grid.insert(coords, wall.id());
+            objs.insert(wall.id(), wall);
+            // nobody could reach a flag inside a wall
+            for flag in flags.values_mut() {
+                if flag.carrier.is_none() && flag.coords == coords {
+                    flag.coords = flag.base;
+                }
+            }
+        }
+    }
+
+    /// Removes the units standing on spawn points, returning the team of each of them
+    fn clear_spawn(&mut self) -> Vec<Team> {
         let Self {
             grid,
             objs,
             spawn_points,
+            flags,
             ..
         } = self;
+        let mut removed = Vec::new();
         for coords in spawn_points.iter() {
-            if let Some(id) = grid.get(coords) {
-                if let Some(Obj(_, ObjDetails::Unit(_))) = objs.get_mut(id) {
-                    objs.remove(id).unwrap();
+            if let Some(&id) = grid.get(coords) {
+                if let Some(Obj(_, ObjDetails::Unit(unit))) = objs.get_mut(&id) {
+                    removed.push(unit.team);
+                    Self::drop_flags(flags, id);
+                    objs.remove(&id).unwrap();
                     grid.remove(coords).unwrap();
                 }
             }
         }
+        removed
     }
 
-    fn spawn_units(&mut self, is_initial: bool) {
+    /// Spawns a wave of units, returning how many units each team got
+    fn spawn_units(&mut self, is_initial: bool) -> usize {
         let Self {
             spawn_points,
             grid,
             objs,
             settings,
+            teams,
+            ids,
+            rng,
             ..
         } = self;
+        let mut spawned = 0;
         if let Some(spawn_settings) = &settings.spawn_settings {
+            let grid_size = settings.grid_size;
+            let team_count = teams.len();
+            // a point is available if it and all of its rotations are free spawn points, so that
+            // every team can be given an equivalent spawn
             let mut available_points = spawn_points
                 .iter()
                 .copied()
-                .filter(|loc| {
-                    !grid.contains_key(loc) && !grid.contains_key(&Self::mirror_loc(*loc))
+                .filter(|&loc| {
+                    let locs = Self::symmetric_locs(loc, team_count, grid_size);
+                    locs.iter().enumerate().all(|(i, rotated)| {
+                        spawn_points.binary_search(rotated).is_ok()
+                            && !grid.contains_key(rotated)
+                            && (i == 0 || *rotated != loc)
+                    })
                 })
                 .collect::<Vec<_>>();
 
@@ -211,32 +699,92 @@ impl State {
             } else {
                 spawn_settings.recurrent_unit_num
             };
-            let mut rng = self.rng.clone();
-            let it = (0..unit_num).flat_map(|_| {
-                let point = available_points.choose(&mut rng).copied();
-                let mirrors = point.map(|point| {
-                    binary_remove(&mut available_points, &point);
-                    let mirror = Self::mirror_loc(point);
-                    binary_remove(&mut available_points, &mirror);
-                    (point, mirror)
-                });
-                mirrors.into_iter().flat_map(|(blue_spawn, red_spawn)| {
-                    Iterator::chain(
-                        std::iter::once((Team::Blue, blue_spawn)),
-                        std::iter::once((Team::Red, red_spawn)),
-                    )
-                    .map(|(team, loc)| {
-                        let obj = Obj::new_unit(UnitType::Soldier, loc, team);
-                        (obj.id(), obj)
-                    })
-                })
-            });
-            let it = it.inspect(|(id, obj)| {
-                grid.insert(obj.coords(), *id);
+            for _ in 0..unit_num {
+                if let Some(&point) = choose(&available_points, rng) {
+                    let locs = Self::symmetric_locs(point, team_count, grid_size);
+                    available_points.retain(|loc| !locs.contains(loc));
+                    // only draw from the rng when there's a choice, so that matches with just one
+                    // unit type play out the same as before there were several
+                    let type_ = match &spawn_settings.unit_types[..] {
+                        [type_] => *type_,
+                        types => choose(types, rng).copied().unwrap_or(UnitType::Soldier),
+                    };
+                    for (&team, loc) in teams.iter().zip(locs) {
+                        let health = settings.unit_stats[type_].health;
+                        let obj = Obj::new_unit(ids.new_id(), type_, loc, team, health);
+                        grid.insert(loc, obj.id());
+                        objs.insert(obj.id(), obj);
+                    }
+                    spawned += 1;
+                }
+            }
+        }
+        spawned
+    }
+
+    /// Spawns a wave of units for `Settings::economy`, where each team gets the units of its spawn
+    /// order that it can pay for, in order, on the spawn points on its side of the grid. Returns
+    /// how many units each team got.
+    fn buy_units(&mut self) -> BTreeMap<Team, usize> {
+        let Self {
+            spawn_points,
+            grid,
+            objs,
+            settings,
+            teams,
+            ids,
+            rng,
+            resources,
+            spawn_orders,
+            ..
+        } = self;
+        let grid_size = settings.grid_size;
+        let team_count = teams.len();
+        // a point belongs to the team whose side of the grid it's on, going by its position
+        // among the symmetric equivalents of the first of them
+        let mut team_points = BTreeMap::<Team, Vec<Coords>>::new();
+        for &loc in spawn_points.iter().filter(|loc| !grid.contains_key(loc)) {
+            let first = Self::symmetric_locs(loc, team_count, grid_size)
+                .into_iter()
+                .min()
+                .unwrap();
+            let locs = Self::symmetric_locs(first, team_count, grid_size);
+            let is_symmetric = locs.iter().enumerate().all(|(i, rotated)| {
+                spawn_points.binary_search(rotated).is_ok() && (i == 0 || *rotated != first)
             });
-            objs.extend(it);
-            self.rng = rng;
+            if !is_symmetric {
+                continue;
+            }
+            let index = locs.iter().position(|&rotated| rotated == loc).unwrap();
+            if let Some(&team) = teams.get(index) {
+                team_points.entry(team).or_default().push(loc);
+            }
+        }
+
+        let mut spawned = BTreeMap::new();
+        for &team in teams.iter() {
+            let order = spawn_orders.remove(&team).unwrap_or_default();
+            let points = team_points.entry(team).or_default();
+            let budget = resources.entry(team).or_default();
+            let count = spawned.entry(team).or_insert(0);
+            for type_ in order {
+                let stats = &settings.unit_stats[type_];
+                if stats.cost > *budget {
+                    break;
+                }
+                let loc = match choose(points, rng) {
+                    Some(&loc) => loc,
+                    None => break,
+                };
+                points.retain(|&point| point != loc);
+                *budget -= stats.cost;
+                let obj = Obj::new_unit(ids.new_id(), type_, loc, team, stats.health);
+                grid.insert(loc, obj.id());
+                objs.insert(obj.id(), obj);
+                *count += 1;
+            }
         }
+        spawned
     }
 
     fn create_team_map(objs: &ObjMap, all_teams: &[Team]) -> TeamMap {
@@ -250,16 +798,16 @@ impl State {
     }
 }
 
-fn determine_winner_from_units_count(units_count: BTreeMap<Team, i32>) -> Option<Team> {
+fn determine_winner_from_scores(scores: &BTreeMap<Team, usize>) -> Option<Team> {
     // find the team that has the high score
-    units_count.values().max().and_then(|&max| {
-        let mut winners = units_count.clone().into_iter().filter(|(_, c)| *c == max);
+    scores.values().max().and_then(|&max| {
+        let mut winners = scores.iter().filter(|(_, c)| **c == max);
         let mut winner = winners.next();
         // if there are multiple teams tied for `max` score, no-one wins
         if winners.next().is_some() {
             winner = None
         }
-        winner.map(|(team, _)| team)
+        winner.map(|(&team, _)| team)
     })
 }
 
@@ -272,58 +820,89 @@ fn determine_winner_normal(state: &StateForOutput) -> Option<Team> {
             *count += 1;
         }
     }
-    determine_winner_from_units_count(units_count)
+    determine_winner_from_scores(&units_count)
 }
 
-const HILL_COORDS: [(usize, usize); 9] = [(9, 9), (8, 9), (8, 8), (9, 8), (10, 8), (10, 9), (10, 10), (9, 10), (8, 10)];
-
-fn determine_winner_hill(turns: &Vec<CallbackInput>, grids: &Vec<GridMap>) -> Option<Team> {
-    let mut units_count = BTreeMap::new();
-    // the highest score any of the teams have
-    let mut max = 0;
-    for (i, (turn, grid)) in turns.iter().zip(grids.iter()).enumerate() {
-        // We don't count the last turn since everyone automatically passes there
-        if i == turns.len() - 1 {
-            break
-        }
-
-        let objs = &turn.state.objs;
-        for (x, y) in HILL_COORDS {
-            if let Some(id) = grid.get(&Coords(x, y)) {
-                let obj = objs.get(id).unwrap();
-                let action = turn.robot_actions.get(id).unwrap();
-                if let (ObjDetails::Unit(unit), Ok(None)) = (obj.details(), action) {
-                    let count = units_count.entry(unit.team).or_insert(0);
-                    *count += 1;
-                    if *count > max {
-                        max = *count
-                    }
-                }
-            }
-        }
-    }
-
-    determine_winner_from_units_count(units_count)
+/// The 3x3 block of cells around the center of the grid
+fn default_hill_coords(grid_size: usize) -> impl Iterator<Item = Coords> {
+    let center = grid_size / 2;
+    let range = center - 1..=center + 1;
+    range
+        .clone()
+        .flat_map(move |x| range.clone().map(move |y| Coords(x, y)))
 }
 
 impl<'a> ProgramInput<'a> {
-    pub fn new(
-        turn_state: &'a TurnState,
-        all_teams: &[Team],
-        team: Team,
-        grid_size: usize,
-    ) -> Self {
+    pub fn new(turn_state: &'a TurnState, all_teams: &[Team], team: Team) -> Self {
         let TurnState { turn, ref state } = *turn_state;
-        let teams = State::create_team_map(&state.objs, all_teams);
+        let visible = state
+            .settings
+            .fog_of_war
+            .as_ref()
+            .map(|fog| state.visible_units(team, fog));
+        let (objs, grid): (MaybeOwned<_>, MaybeOwned<_>) = match &visible {
+            None => ((&state.objs).into(), (&state.grid).into()),
+            Some(visible) => {
+                let objs = state
+                    .objs
+                    .iter()
+                    .filter(|(id, obj)| {
+                        matches!(obj.details(), ObjDetails::Terrain(_)) || visible.contains(id)
+                    })
+                    .map(|(&id, obj)| (id, obj.clone()))
+                    .collect::<ObjMap>();
+                let grid = state
+                    .grid
+                    .iter()
+                    .filter(|(_, id)| objs.contains_key(id))
+                    .map(|(&coords, &id)| (coords, id))
+                    .collect::<GridMap>()
+                    .with_size(state.settings.grid_size);
+                (objs.into(), grid.into())
+            }
+        };
+        let teams = State::create_team_map(&objs, all_teams);
+        let flags = state
+            .flags
+            .iter()
+            .map(|(&flag_team, flag)| {
+                let hidden = flag.carrier.filter(|id| !objs.contains_key(id)).is_some();
+                let flag = FlagForProgramInput {
+                    base: flag.base,
+                    coords: Some(flag.coords).filter(|_| !hidden),
+                    carrier: flag.carrier.filter(|_| !hidden),
+                };
+                (flag_team, flag)
+            })
+            .collect();
+        let resources = match visible {
+            None => state.resources.clone(),
+            Some(_) => state
+                .resources
+                .get(&team)
+                .map(|&resources| (team, resources))
+                .into_iter()
+                .collect(),
+        };
         Self {
             state: StateForProgramInput {
                 turn,
-                objs: (&state.objs).into(),
-                grid: (&state.grid).into(),
+                objs,
+                grid,
+                terrain_grid: (&state.terrain_grid).into(),
                 teams,
             },
             team,
-            grid_size,
+            grid_size: state.settings.grid_size,
+            unit_stats: (&state.settings.unit_stats).into(),
+            directions: state.settings.directions,
+            spawn_points: (&state.spawn_points).into(),
+            hill: state.settings.hill_coords(),
+            hill_score: state.hill_score.clone(),
+            flags,
+            captures: state.captures.clone(),
+            resources,
+            zone: state.zone.clone(),
         }
     }
 }
@@ -332,18 +911,24 @@ fn validate_robot_action(
     action: ActionResult,
     team: Team,
     id: Id,
-    objs: &ObjMap,
+    turn_state: &TurnState,
 ) -> ValidatedRobotAction {
     action
         .map_err(RobotErrorAfterValidation::RuntimeError)
         .and_then(|action| {
-            let err_msg = match objs.get(&id).map(|obj| obj.details()) {
-                Some(ObjDetails::Unit(unit)) if unit.team != team => {
+            let TurnState { turn, ref state } = *turn_state;
+            let err_msg = match state.objs.get(&id).map(|obj| (obj.coords(), obj.details())) {
+                Some((_, ObjDetails::Unit(unit))) if unit.team != team => {
                     "Action ID points to unit on other team"
                 }
-                Some(ObjDetails::Terrain(_)) => "Action ID points to terrain",
+                Some((coords, ObjDetails::Unit(unit))) => {
+                    match action.map(|action| state.check_unit_action(unit, coords, action, turn)) {
+                        Some(Err(err_msg)) => err_msg,
+                        _ => return Ok(action),
+                    }
+                }
+                Some((_, ObjDetails::Terrain(_))) => "Action ID points to terrain",
                 None => "Action ID points to nonexistent object",
-                _ => return Ok(action),
             };
             Err(RobotErrorAfterValidation::InvalidAction(err_msg.to_owned()))
         })
@@ -362,6 +947,9 @@ fn handle_program_errors(
     errors: ErrorMap,
     all_teams: &[Team],
     turns: Vec<CallbackInput>,
+    settings: Settings,
+    seed: Option<String>,
+    stats: BTreeMap<Team, TeamStats>,
 ) -> MainOutput {
     let mut winner = Some(None);
     for team in all_teams {
@@ -380,10 +968,20 @@ fn handle_program_errors(
         winner: winner.flatten(),
         errors,
         turns,
+        settings,
+        seed,
+        termination: Termination::Error,
+        stats,
     }
 }
 
+/// The most teams a match can have, limited by the 4-fold symmetry of the grid
+pub const MAX_TEAMS: usize = 4;
+
+/// The default grid size, used when `Settings` doesn't specify one
 pub const GRID_SIZE: usize = 19;
+pub const MIN_GRID_SIZE: usize = 5;
+pub const MAX_GRID_SIZE: usize = 99;
 
 #[cfg_attr(not(feature = "robot-runner-not-send"), async_trait::async_trait)]
 #[cfg_attr(feature = "robot-runner-not-send", async_trait::async_trait(? Send))]
@@ -415,17 +1013,20 @@ pub async fn run<TurnCb, R>(
     settings_option: Option<Settings>,
     game_mode: GameMode,
     seed: Option<&str>,
-) -> MainOutput
+) -> Result<MainOutput, SettingsError>
 where
     TurnCb: FnMut(&CallbackInput),
     R: RobotRunner,
 {
-    reset_id();
-    let settings = settings_option.unwrap_or_default();
-
-    // all_teams is the list of all the teams participating in the battle
-    let all_teams = runners.keys().copied().collect::<Box<[_]>>();
-    let all_teams = &*all_teams;
+    let mut game = Game::new(MatchConfig {
+        // all the teams participating in the battle
+        teams: runners.keys().copied().collect(),
+        max_turn,
+        dev_mode,
+        settings: settings_option.unwrap_or_default(),
+        game_mode,
+        seed: seed.map(ToOwned::to_owned),
+    })?;
 
     let mut run_funcs = BTreeMap::new();
     let mut errors = ErrorMap::new();
@@ -435,150 +1036,77 @@ where
         }
     }
     if !errors.is_empty() {
-        return handle_program_errors(errors, all_teams, vec![]);
+        return Ok(game.finish_with_errors(errors));
     }
 
-    let mut turns = Vec::with_capacity(max_turn);
-    let mut grids = Vec::with_capacity(max_turn);
-
-    let mut turn_state = TurnState {
-        turn: 1,
-        state: State::new(MapType::Circle, GRID_SIZE, settings.clone(), seed),
-    };
-    while turn_state.turn <= max_turn {
-        if let Some(spawn_settings) = &settings.spawn_settings {
-            if turn_state.turn == 1 {
-                turn_state.state.spawn_units(true);
-            } else if spawn_settings.spawn_every != 0 {
-                if (turn_state.turn - 1) % spawn_settings.spawn_every == 0 {
-                    turn_state.state.clear_spawn();
-                    turn_state.state.spawn_units(false);
+    while !game.is_finished() {
+        let runners = run_funcs.iter_mut().map(|(&t, r)| (t, r));
+        match get_turn_data(runners, &game).await {
+            Ok(outputs) => {
+                for (team, output) in outputs {
+                    game.submit(team, output);
                 }
             }
+            Err(errors) => return Ok(game.finish_with_errors(errors)),
         }
 
-        let runners = run_funcs.iter_mut().map(|(&t, r)| (t, r));
-        let turn = match get_turn_data(runners, all_teams, &turn_state, dev_mode).await {
-            Ok(t) => t,
-            Err(errors) => return handle_program_errors(errors, all_teams, turns),
-        };
-
-        if game_mode == GameMode::Hill {
-            grids.push(turn_state.state.grid.clone());
-        }
-
-        // update turn_state
-        run_turn(&turn.robot_actions, &mut turn_state.state, game_mode);
-
-        // but the new state isn't passed until the next cycle since it's not yet reflected in `turn`
+        let turn = game.step();
         turn_cb(&turn);
-        turns.push(turn);
-
-        turn_state.turn += 1;
     }
 
-    let final_turn = CallbackInput {
-        state: StateForOutput {
-            objs: turn_state.state.objs.clone(),
-            turn: turn_state.turn,
-        },
-        robot_actions: turn_state
-            .state
-            .objs
-            .iter()
-            .map(|(k, _)| (*k, Ok(None)))
-            .collect::<BTreeMap<_, _>>(),
-        ..Default::default()
-    };
-
-    // add the final turn after the last robot actions
-    turn_cb(&final_turn);
-    turns.push(final_turn);
-    if game_mode == GameMode::Hill {
-        grids.push(turn_state.state.grid.clone());
-    }
-
-    use GameMode::*;
-    let winner = match game_mode {
-        Normal | NormalHeal => determine_winner_normal(&turns.last().unwrap().state),
-        Hill => determine_winner_hill(&turns, &grids)
-    };
-    MainOutput {
-        winner,
-        errors: BTreeMap::new(),
-        turns,
-    }
+    let output = game.finish();
+    // the final turn after the last robot actions
+    turn_cb(output.turns.last().unwrap());
+    Ok(output)
 }
 
 async fn get_turn_data<'r, R: RobotRunner + 'r>(
     runners: impl Iterator<Item = (Team, &'r mut R)>,
-    all_teams: &[Team],
-    turn_state: &TurnState,
-    dev_mode: bool,
-) -> Result<CallbackInput, ErrorMap> {
+    game: &Game,
+) -> Result<Vec<(Team, ProgramOutput)>, ErrorMap> {
     let mut errors = ErrorMap::new();
-
-    let mut turn = CallbackInput {
-        state: StateForOutput {
-            objs: turn_state.state.objs.clone(),
-            turn: turn_state.turn,
-        },
-        robot_actions: BTreeMap::new(),
-        logs: BTreeMap::new(),
-        debug_locate_queries: BTreeMap::new(),
-        debug_inspect_tables: BTreeMap::new(),
-    };
+    let mut outputs = Vec::new();
 
     let mut results: stream::FuturesUnordered<_> = runners
         .map(|(team, runner)| {
             runner
-                .run(ProgramInput::new(&turn_state, all_teams, team, GRID_SIZE))
+                .run(game.current_input(team))
                 .map(move |program_result| (team, program_result))
         })
         .collect();
 
     while let Some((team, result)) = results.next().await {
-        let runner_output = match check_runner_error(&mut errors, team, result) {
-            Some(o) => o,
-            None => continue,
-        };
-        turn.robot_actions
-            .extend(runner_output.robot_actions.into_iter().map(|(id, action)| {
-                (
-                    id,
-                    validate_robot_action(action, team, id, &turn_state.state.objs),
-                )
-            }));
-        turn.logs.insert(team, runner_output.logs);
-        if dev_mode {
-            turn.debug_locate_queries
-                .insert(team, runner_output.debug_locate_queries);
-            if runner_output
-                .debug_inspect_tables
-                .keys()
-                .all(|id| is_id_valid(team, *id, &turn_state.state.objs))
-            {
-                turn.debug_inspect_tables
-                    .extend(runner_output.debug_inspect_tables);
-            }
+        if let Some(output) = check_runner_error(&mut errors, team, result) {
+            outputs.push((team, output));
         }
     }
 
     if errors.is_empty() {
-        Ok(turn)
+        Ok(outputs)
     } else {
         Err(errors)
     }
 }
 
+/// What happened during a turn, besides the changes to the state
+#[derive(Debug, Default)]
+struct TurnEvents {
+    move_conflicts: Vec<MoveConflict>,
+    /// The stats of each team for just this turn, for the teams that had anything happen to them
+    stats: BTreeMap<Team, TeamStats>,
+}
+
 fn run_turn(
     robot_actions: &BTreeMap<Id, ValidatedRobotAction>,
     state: &mut State,
     game_mode: GameMode,
-) {
+) -> TurnEvents {
+    let mut events = TurnEvents::default();
     let mut movement_map = MultiMap::new();
-    let mut attack_map = MultiMap::new();
+    let mut movers = Vec::new();
+    let mut attacks = Vec::new();
     let mut heal_map = MultiMap::new();
+    let mut harvesters = Vec::new();
 
     for (id, action) in robot_actions.iter().filter_map(|(id, action)| {
         action
@@ -586,100 +1114,1264 @@ fn run_turn(
             .ok()
             .and_then(|maybe_a| maybe_a.map(|a| (id, a)))
     }) {
-        let map = match action.type_ {
-            ActionType::Move => &mut movement_map,
-            ActionType::Attack => &mut attack_map,
-            ActionType::Heal => {
-                if game_mode == GameMode::NormalHeal {
-                    &mut heal_map
-                } else {
-                    continue;
+        let obj = state.objs.get(&id).unwrap();
+        let unit = match obj.details() {
+            ObjDetails::Unit(unit) => unit,
+            ObjDetails::Terrain(_) => continue,
+        };
+        if action.type_ == ActionType::Move {
+            movers.push((*id, unit.team));
+        }
+        let target = obj.coords() + action.direction;
+        let size = state.settings.grid_size;
+        match action.type_ {
+            // a step off the edge of the grid goes nowhere
+            ActionType::Move if target == obj.coords() || target.0 >= size || target.1 >= size => {}
+            ActionType::Move => movement_map.insert(target, (*id, action.direction)),
+            // where an attack lands depends on where units end up after moving
+            ActionType::Attack => attacks.push((*id, action.direction)),
+            // medics can always heal, everyone else only in `NormalHeal`
+            ActionType::Heal
+                if game_mode == GameMode::NormalHeal || unit.type_ == UnitType::Medic =>
+            {
+                let heal_power = state.settings.unit_stats[unit.type_].heal_power;
+                heal_map.insert(target, (unit.team, heal_power))
+            }
+            ActionType::Heal => {}
+            // resource nodes block movement, so they're on the main grid
+            ActionType::Harvest => {
+                if let Some(ObjDetails::Terrain(terrain)) =
+                    state.grid.get(&target).map(|id| state.objs[id].details())
+                {
+                    if terrain.type_ == TerrainType::Resource {
+                        harvesters.push(unit.team);
+                    }
                 }
             }
-        };
-        let obj = state.objs.get(&id).unwrap();
-        map.insert(obj.coords() + action.direction, (*id, action.direction));
-    }
-
-    let movement_grid = movement_map
-        .iter_all()
-        .filter_map(|(coords, robots)| {
-            let robot_chosen_to_move = if robots.len() > 1 {
-                robots.iter().min_by_key(|(_, direction)| match direction {
-                    Direction::North => 1,
-                    Direction::East => 2,
-                    Direction::South => 3,
-                    Direction::West => 4,
-                })
-            } else {
-                robots.get(0)
-            };
-            robot_chosen_to_move.map(|r| (coords, r))
-        })
-        .collect::<HashMap<_, _>>();
+        }
+    }
+    if let Some(economy) = &state.settings.economy {
+        for team in harvesters {
+            *state.resources.entry(team).or_default() += economy.harvest_amount;
+        }
+    }
 
-    let movement_grid = movement_grid
-        .into_iter()
-        .filter(|(&coords, &(_, direction))| {
-            let origin_coords = coords + direction.opposite();
-            match movement_map.get(&origin_coords) {
-                Some((_, direction2)) => direction != direction2.opposite(),
-                None => true,
-            }
-        })
-        .map(|(&coords, &(id, _))| (coords, id))
-        .collect::<GridMap>();
+    let objs = &state.objs;
+    let health = |id: Id| match objs[&id].details() {
+        ObjDetails::Unit(unit) => unit.health,
+        ObjDetails::Terrain(_) => 0,
+    };
+    let mut cells = movement_map.iter_all().collect::<Vec<_>>();
+    // sorted so that random picks only depend on the seed
+    cells.sort_unstable_by_key(|&(&coords, _)| coords);
+    let mut moves = HashMap::new();
+    for (&coords, robots) in cells {
+        if let [(id, _)] = robots[..] {
+            moves.insert(id, coords);
+            continue;
+        }
+        let mut robots = robots.clone();
+        robots.sort_unstable_by_key(|&(id, _)| id);
+        let winner = match state.settings.move_conflicts {
+            ConflictPolicy::DirectionPriority => robots
+                .iter()
+                .min_by_key(|&&(_, direction)| direction_priority(direction)),
+            ConflictPolicy::Random => choose(&robots, &mut state.rng),
+            ConflictPolicy::NobodyMoves => None,
+            ConflictPolicy::HealthiestWins => robots.iter().min_by_key(|&&(id, direction)| {
+                (std::cmp::Reverse(health(id)), direction_priority(direction))
+            }),
+        }
+        .map(|&(id, _)| id);
+        if let Some(id) = winner {
+            moves.insert(id, coords);
+        }
+        events.move_conflicts.push(MoveConflict {
+            coords,
+            contenders: robots.iter().map(|&(id, _)| id).collect(),
+            winner,
+        });
+    }
 
-    state
-        .grid
-        .retain(|_, id| !movement_grid.values().any(|movement_id| id == movement_id));
-    update_grid_with_movement(&mut state.objs, &mut state.grid, movement_grid);
+    let moves = resolve_movement(&state.objs, &state.grid, moves);
+    let moved = moves.iter().map(|&(id, _)| id).collect::<HashSet<_>>();
+    for (id, team) in movers {
+        if !moved.contains(&id) {
+            events.stats.entry(team).or_default().moves_blocked += 1;
+        }
+    }
+    for (id, _) in &moves {
+        state.grid.remove(&state.objs[id].coords());
+    }
+    for (id, coords) in moves {
+        state.objs.get_mut(&id).unwrap().0.coords = coords;
+        state.grid.insert(coords, id);
+    }
+    if game_mode == GameMode::CaptureTheFlag {
+        state.carry_flags();
+    }
 
-    for (coords, attacks) in attack_map.iter_all() {
-        if let Some(id) = state.grid.get(coords) {
-            if let Some(Obj(_, ObjDetails::Unit(unit))) = state.objs.get_mut(id) {
-                unit.health = unit
-                    .health
-                    .saturating_sub(attacks.len() * Obj::ATTACK_POWER);
+    let mut damage_map = BTreeMap::new();
+    for (id, direction) in attacks {
+        let obj = state.objs.get(&id).unwrap();
+        if let ObjDetails::Unit(unit) = obj.details() {
+            let stats = &state.settings.unit_stats[unit.type_];
+            let target = attack_target(
+                &state.grid,
+                obj.coords(),
+                direction,
+                stats.attack_range,
+                state.settings.grid_size,
+            );
+            if let Some(coords) = target {
+                damage_map
+                    .entry(coords)
+                    .or_insert_with(Vec::new)
+                    .push((unit.team, stats.attack_power));
+            }
+        }
+    }
+
+    for (coords, hits) in damage_map {
+        // cover absorbs the first points of damage
+        let mut cover = match state.terrain_at(coords) {
+            Some(TerrainType::Cover) => TerrainType::COVER_PROTECTION,
+            _ => 0,
+        };
+        let id = match state.grid.get(&coords) {
+            Some(&id) => id,
+            None => continue,
+        };
+        match state.objs.get_mut(&id) {
+            Some(Obj(_, ObjDetails::Unit(unit))) => {
+                let health = unit.health;
+                // the team whose attack takes the last point of health gets the kill
+                let mut last_hit = None;
+                for (team, power) in hits {
+                    let absorbed = cover.min(power);
+                    cover -= absorbed;
+                    let damage = (power - absorbed).min(unit.health);
+                    if damage > 0 {
+                        unit.health -= damage;
+                        if team != unit.team {
+                            events.stats.entry(team).or_default().damage_dealt += damage;
+                        }
+                        last_hit = Some(team);
+                    }
+                }
+                let team = unit.team;
+                let stats = events.stats.entry(team).or_default();
+                stats.damage_received += health - unit.health;
                 if unit.health == 0 {
-                    state.objs.remove(id).unwrap();
-                    state.grid.remove(coords).unwrap();
+                    stats.units_lost += 1;
+                    if let Some(killer) = last_hit.filter(|&killer| killer != team) {
+                        events.stats.entry(killer).or_default().kills += 1;
+                    }
+                    state.objs.remove(&id).unwrap();
+                    state.grid.remove(&coords).unwrap();
                 }
             }
+            // only destructible terrain has health. Cover and stats are only for units, so every
+            // hit simply adds up
+            Some(Obj(_, ObjDetails::Terrain(terrain))) if terrain.health.is_some() => {
+                let damage = hits.iter().map(|&(_, power)| power).sum::<usize>();
+                let health = terrain.health.unwrap().saturating_sub(damage);
+                terrain.health = Some(health);
+                if health == 0 {
+                    state.objs.remove(&id).unwrap();
+                    state.grid.remove(&coords).unwrap();
+                }
+            }
+            _ => {}
         }
     }
 
     for (coords, heals) in heal_map.iter_all() {
         if let Some(id) = state.grid.get(coords) {
             if let Some(Obj(_, ObjDetails::Unit(unit))) = state.objs.get_mut(id) {
-                unit.health = usize::min(
-                    Obj::UNIT_HEALTH,
-                    unit.health + heals.len() * Obj::HEAL_POWER,
-                );
+                let max_health = state.settings.unit_stats[unit.type_].max_health;
+                for &(team, heal_power) in heals {
+                    let healed = heal_power.min(max_health.saturating_sub(unit.health));
+                    unit.health += healed;
+                    events.stats.entry(team).or_default().healing_done += healed;
+                }
             }
         }
     }
+
+    apply_terrain_effects(state, &mut events.stats);
+    if game_mode == GameMode::CaptureTheFlag {
+        state.update_flags();
+    }
+
+    events
 }
 
-pub fn update_grid_with_movement(objs: &mut ObjMap, grid: &mut GridMap, movement_grid: GridMap) {
-    let mut legal_moves = movement_grid;
-    loop {
-        let (illegal_moves, new_legal_moves): (GridMap, GridMap) = legal_moves
-            .into_iter()
-            .partition(|(coords, _)| grid.contains_key(coords));
-        legal_moves = new_legal_moves;
+fn direction_priority(direction: Direction) -> u8 {
+    match direction {
+        Direction::North => 1,
+        Direction::East => 2,
+        Direction::South => 3,
+        Direction::West => 4,
+        Direction::NorthEast => 5,
+        Direction::SouthEast => 6,
+        Direction::SouthWest => 7,
+        Direction::NorthWest => 8,
+    }
+}
+
+/// Heals the units standing on fountains and damages the ones standing on lava
+fn apply_terrain_effects(state: &mut State, stats: &mut BTreeMap<Team, TeamStats>) {
+    let State {
+        objs,
+        grid,
+        terrain_grid,
+        settings,
+        ..
+    } = state;
+    for (coords, terrain_id) in terrain_grid.iter() {
+        let type_ = match objs.get(terrain_id).map(|obj| obj.details()) {
+            Some(ObjDetails::Terrain(terrain)) => terrain.type_,
+            _ => continue,
+        };
+        let unit_id = match grid.get(coords) {
+            Some(id) => *id,
+            None => continue,
+        };
+        if let Some(Obj(_, ObjDetails::Unit(unit))) = objs.get_mut(&unit_id) {
+            match type_ {
+                TerrainType::Fountain => {
+                    let max_health = settings.unit_stats[unit.type_].max_health;
+                    let healed =
+                        TerrainType::FOUNTAIN_HEAL.min(max_health.saturating_sub(unit.health));
+                    unit.health += healed;
+                    stats.entry(unit.team).or_default().healing_done += healed;
+                }
+                TerrainType::Lava => {
+                    let damage = TerrainType::LAVA_DAMAGE.min(unit.health);
+                    unit.health -= damage;
+                    let stats = stats.entry(unit.team).or_default();
+                    stats.damage_received += damage;
+                    if unit.health == 0 {
+                        stats.units_lost += 1;
+                        objs.remove(&unit_id).unwrap();
+                        grid.remove(coords).unwrap();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Finds the cell that an attack from `from` in `direction` hits: the first occupied one within
+/// `range` cells, if there is one before the edge of the grid.
+fn attack_target(
+    grid: &GridMap,
+    from: Coords,
+    direction: Direction,
+    range: usize,
+    grid_size: usize,
+) -> Option<Coords> {
+    let mut coords = from;
+    for _ in 0..range {
+        let next = coords + direction;
+        if next == coords || next.0 >= grid_size || next.1 >= grid_size {
+            return None;
+        }
+        coords = next;
+        if grid.contains_key(&coords) {
+            return Some(coords);
+        }
+    }
+    None
+}
+
+/// Decides which moves happen, given at most one unit moving into each cell after conflicts have
+/// been settled. `moves` maps the
+/// moving units to their destinations, and the moves that succeed are returned.
+///
+/// - Two units that try to swap places both stay put.
+/// - A move fails if its destination is taken by an object that stays put, whether that's a wall, a
+///   unit that isn't moving, or a unit whose own move failed.
+/// - Every other move succeeds. So a train of units, each moving into the cell of the one in front
+///   of it, moves as a whole if its leader moves into a free cell, and stays put as a whole if the
+///   leader is blocked. A closed cycle of three or more units, like four units rotating around a
+///   2x2 block, moves as a whole too, since each destination is vacated at the same time.
+fn resolve_movement(
+    objs: &ObjMap,
+    grid: &GridMap,
+    moves: HashMap<Id, Coords>,
+) -> Vec<(Id, Coords)> {
+    let origin = |id: &Id| objs[id].coords();
+    // the unit moving into each cell
+    let movers = moves
+        .iter()
+        .map(|(&id, &coords)| (coords, id))
+        .collect::<HashMap<_, _>>();
+
+    let mut failed = moves
+        .iter()
+        .filter(|&(id, dest)| match grid.get(dest) {
+            None => false,
+            Some(occupant) => match moves.get(occupant) {
+                None => true,
+                Some(&occupant_dest) => occupant != id && occupant_dest == origin(id),
+            },
+        })
+        .map(|(&id, _)| id)
+        .collect::<Vec<_>>();
+    let mut stays = HashSet::new();
+    // a unit that stays put blocks the unit moving into its cell, if there is one
+    while let Some(id) = failed.pop() {
+        if stays.insert(id) {
+            if let Some(&blocked) = movers.get(&origin(&id)) {
+                failed.push(blocked);
+            }
+        }
+    }
+
+    moves
+        .into_iter()
+        .filter(|(id, _)| !stays.contains(id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn initial_spawns(seed: &str, team_count: u8) -> Vec<(Team, Coords)> {
+        let teams = (0..team_count).map(Team).collect::<Vec<_>>();
+        let mut state = State::new(Settings::default(), &teams, Some(seed)).unwrap();
+        state.spawn_units(true);
+        let mut spawns = state
+            .objs
+            .values()
+            .filter_map(|obj| match obj.details() {
+                ObjDetails::Unit(unit) => Some((unit.team, obj.coords())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        spawns.sort();
+        spawns
+    }
+
+    #[test]
+    fn seed_is_stable() {
+        assert_eq!(
+            hex(&string_to_seed("")),
+            "30ffa46b017c81c31693bcc0acda0c101b1bf6ec69a5c354721c65240792652c"
+        );
+        assert_eq!(
+            hex(&string_to_seed("robot-rumble")),
+            "668484c4b58ded51ff244c8ee308ab1e0518a422dfcb55351a9b0444c6ffff62"
+        );
+    }
+
+    #[test]
+    fn spawn_layout_is_stable() {
+        let (red, blue) = (Team::Red, Team::Blue);
+        assert_eq!(
+            initial_spawns("robot-rumble", 2),
+            [
+                (red, Coords(1, 9)),
+                (red, Coords(4, 16)),
+                (red, Coords(9, 1)),
+                (red, Coords(17, 13)),
+                (blue, Coords(1, 5)),
+                (blue, Coords(9, 17)),
+                (blue, Coords(14, 2)),
+                (blue, Coords(17, 9)),
+            ]
+        );
+        assert_eq!(
+            initial_spawns("42", 2),
+            [
+                (red, Coords(1, 13)),
+                (red, Coords(3, 3)),
+                (red, Coords(11, 1)),
+                (red, Coords(13, 17)),
+                (blue, Coords(5, 1)),
+                (blue, Coords(7, 17)),
+                (blue, Coords(15, 15)),
+                (blue, Coords(17, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn three_team_spawn_layout_is_stable() {
+        // the same as with 4 teams, with the last quarter of the grid left empty
+        let (red, blue, green) = (Team::Red, Team::Blue, Team::Green);
+        assert_eq!(
+            initial_spawns("robot-rumble", 3),
+            [
+                (red, Coords(1, 10)),
+                (red, Coords(4, 2)),
+                (red, Coords(9, 1)),
+                (red, Coords(17, 13)),
+                (blue, Coords(5, 17)),
+                (blue, Coords(8, 1)),
+                (blue, Coords(16, 4)),
+                (blue, Coords(17, 9)),
+                (green, Coords(1, 5)),
+                (green, Coords(9, 17)),
+                (green, Coords(14, 16)),
+                (green, Coords(17, 8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn four_team_spawn_layout_is_stable() {
+        let (red, blue, green, yellow) = (Team::Red, Team::Blue, Team::Green, Team::Yellow);
+        assert_eq!(
+            initial_spawns("robot-rumble", 4),
+            [
+                (red, Coords(1, 10)),
+                (red, Coords(4, 2)),
+                (red, Coords(9, 1)),
+                (red, Coords(17, 13)),
+                (blue, Coords(5, 17)),
+                (blue, Coords(8, 1)),
+                (blue, Coords(16, 4)),
+                (blue, Coords(17, 9)),
+                (green, Coords(1, 5)),
+                (green, Coords(9, 17)),
+                (green, Coords(14, 16)),
+                (green, Coords(17, 8)),
+                (yellow, Coords(1, 9)),
+                (yellow, Coords(2, 14)),
+                (yellow, Coords(10, 17)),
+                (yellow, Coords(13, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn team_counts_are_validated() {
+        let settings = Settings::default();
+        for team_count in [0, 5] {
+            assert!(matches!(
+                settings.validate(team_count),
+                Err(SettingsError::InvalidTeamCount(n)) if n == team_count
+            ));
+        }
+        for team_count in 1..=4 {
+            assert!(settings.validate(team_count).is_ok());
+        }
+        // point symmetry is enough for 2 teams, but not for 4
+        let settings = Settings {
+            spawn_points: Some(vec![Coords(1, 5), Coords(17, 13)]),
+            ..Default::default()
+        };
+        assert!(settings.validate(2).is_ok());
+        for team_count in [3, 4] {
+            assert!(matches!(
+                settings.validate(team_count),
+                Err(SettingsError::AsymmetricSpawn(Coords(1, 5)))
+            ));
+        }
+    }
+
+    #[test]
+    fn winners_among_many_teams() {
+        let teams = [Team::Red, Team::Blue, Team::Green, Team::Yellow];
+        let winner = |units: &[usize]| {
+            let mut ids = IdAllocator::default();
+            let objs = teams
+                .iter()
+                .zip(units)
+                .flat_map(|(&team, &count)| vec![team; count])
+                .enumerate()
+                .map(|(i, team)| {
+                    let coords = Coords(i, 0);
+                    let unit = Obj::new_unit(ids.new_id(), UnitType::Soldier, coords, team, 5);
+                    (unit.id(), unit)
+                })
+                .collect();
+            determine_winner_normal(&StateForOutput { objs, turn: 1 })
+        };
+        assert_eq!(winner(&[1, 3, 2]), Some(Team::Blue));
+        assert_eq!(winner(&[3, 1, 3]), None);
+        assert_eq!(winner(&[0, 2, 1, 4]), Some(Team::Yellow));
+        assert_eq!(winner(&[4, 2, 0, 4]), None);
+        // teams without units left don't count
+        assert_eq!(winner(&[0, 0, 1, 0]), Some(Team::Green));
+    }
+
+    /// Places units in the open middle of the default map, with the actions for them to move
+    fn place_movers(
+        settings: Settings,
+        units: &[(Coords, Option<Direction>)],
+    ) -> (State, Vec<Id>, BTreeMap<Id, ValidatedRobotAction>) {
+        let mut state = State::new(settings, &[Team::Red, Team::Blue], Some("movement")).unwrap();
+        let mut actions = BTreeMap::new();
+        let ids = units
+            .iter()
+            .map(|&(coords, direction)| {
+                let id = state.ids.new_id();
+                let unit = Obj::new_unit(id, UnitType::Soldier, coords, Team::Red, 5);
+                state.objs.insert(id, unit);
+                state.grid.insert(coords, id);
+                let action = direction.map(|direction| Action {
+                    type_: ActionType::Move,
+                    direction,
+                });
+                actions.insert(id, Ok(action));
+                id
+            })
+            .collect();
+        (state, ids, actions)
+    }
+
+    /// Has all of `units` move at once, returning where each of them ended up
+    fn move_units(settings: Settings, units: &[(Coords, Option<Direction>)]) -> Vec<Coords> {
+        let (mut state, ids, actions) = place_movers(settings, units);
+        run_turn(&actions, &mut state, GameMode::Normal);
+        for (coords, id) in state.grid.iter() {
+            assert_eq!(state.objs[id].coords(), *coords);
+        }
+        ids.iter().map(|id| state.objs[id].coords()).collect()
+    }
 
-        if illegal_moves.is_empty() {
-            for (&coords, id) in legal_moves.iter() {
-                objs.get_mut(id).unwrap().0.coords = coords
+    #[test]
+    fn swapping_units_stay() {
+        use Direction::*;
+        let units = [(Coords(9, 9), Some(East)), (Coords(10, 9), Some(West))];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(9, 9), Coords(10, 9)]
+        );
+    }
+
+    #[test]
+    fn swap_with_a_losing_contender_stays() {
+        use Direction::*;
+        // the unit moving north into (9, 9) wins over the one moving west, so the swap is between
+        // the first two units, and the third is blocked by the unit that stays
+        let units = [
+            (Coords(9, 9), Some(South)),
+            (Coords(9, 10), Some(North)),
+            (Coords(10, 9), Some(West)),
+        ];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(9, 9), Coords(9, 10), Coords(10, 9)]
+        );
+    }
+
+    #[test]
+    fn priority_decides_who_moves() {
+        use Direction::*;
+        let units = [(Coords(8, 9), Some(East)), (Coords(9, 10), Some(North))];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(8, 9), Coords(9, 9)]
+        );
+    }
+
+    #[test]
+    fn train_follows_its_leader() {
+        use Direction::*;
+        let units = [
+            (Coords(9, 9), Some(East)),
+            (Coords(8, 9), Some(East)),
+            (Coords(7, 9), Some(East)),
+        ];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(10, 9), Coords(9, 9), Coords(8, 9)]
+        );
+    }
+
+    #[test]
+    fn blocked_train_stays() {
+        use Direction::*;
+        let units = [
+            (Coords(10, 9), None),
+            (Coords(9, 9), Some(East)),
+            (Coords(8, 9), Some(East)),
+            (Coords(7, 9), Some(East)),
+        ];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(10, 9), Coords(9, 9), Coords(8, 9), Coords(7, 9)]
+        );
+    }
+
+    #[test]
+    fn train_into_a_wall_stays() {
+        use Direction::*;
+        let units = [(Coords(1, 9), Some(West)), (Coords(2, 9), Some(West))];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(1, 9), Coords(2, 9)]
+        );
+    }
+
+    #[test]
+    fn rotation_moves() {
+        use Direction::*;
+        let units = [
+            (Coords(9, 9), Some(East)),
+            (Coords(10, 9), Some(South)),
+            (Coords(10, 10), Some(West)),
+            (Coords(9, 10), Some(North)),
+        ];
+        assert_eq!(
+            move_units(Settings::default(), &units),
+            [Coords(10, 9), Coords(10, 10), Coords(9, 10), Coords(9, 9)]
+        );
+    }
+
+    #[test]
+    fn three_cycle_moves() {
+        use Direction::*;
+        let settings = Settings {
+            directions: DirectionSet::Eight,
+            ..Default::default()
+        };
+        let units = [
+            (Coords(9, 9), Some(East)),
+            (Coords(10, 9), Some(SouthWest)),
+            (Coords(9, 10), Some(North)),
+        ];
+        assert_eq!(
+            move_units(settings, &units),
+            [Coords(10, 9), Coords(9, 10), Coords(9, 9)]
+        );
+    }
+
+    #[test]
+    fn nobody_moves_into_contested_cell() {
+        use Direction::*;
+        let settings = Settings {
+            move_conflicts: ConflictPolicy::NobodyMoves,
+            ..Default::default()
+        };
+        let units = [
+            (Coords(8, 9), Some(East)),
+            (Coords(9, 10), Some(North)),
+            (Coords(9, 11), Some(North)),
+        ];
+        assert_eq!(
+            move_units(settings, &units),
+            [Coords(8, 9), Coords(9, 10), Coords(9, 11)]
+        );
+    }
+
+    #[test]
+    fn conflicts_are_recorded() {
+        use Direction::*;
+        let settings = Settings {
+            move_conflicts: ConflictPolicy::HealthiestWins,
+            ..Default::default()
+        };
+        let units = [
+            (Coords(8, 9), Some(East)),
+            (Coords(9, 10), Some(North)),
+            (Coords(10, 9), Some(West)),
+        ];
+        let (mut state, ids, actions) = place_movers(settings, &units);
+        if let ObjDetails::Unit(unit) = &mut state.objs.get_mut(&ids[2]).unwrap().1 {
+            unit.health = 4;
+        }
+        let events = run_turn(&actions, &mut state, GameMode::Normal);
+        assert_eq!(
+            events.move_conflicts,
+            [MoveConflict {
+                coords: Coords(9, 9),
+                contenders: ids.clone(),
+                winner: Some(ids[1]),
+            }]
+        );
+        assert_eq!(state.objs[&ids[1]].coords(), Coords(9, 9));
+    }
+
+    #[test]
+    fn random_conflicts_are_reproducible() {
+        use Direction::*;
+        let settings = Settings {
+            move_conflicts: ConflictPolicy::Random,
+            ..Default::default()
+        };
+        let units = [
+            (Coords(8, 9), Some(East)),
+            (Coords(9, 10), Some(North)),
+            (Coords(10, 9), Some(West)),
+            (Coords(9, 8), Some(South)),
+        ];
+        let winners = (0..2)
+            .map(|_| {
+                let (mut state, _, actions) = place_movers(settings.clone(), &units);
+                let events = run_turn(&actions, &mut state, GameMode::Normal);
+                events.move_conflicts[0].winner
+            })
+            .collect::<Vec<_>>();
+        assert!(winners[0].is_some());
+        assert_eq!(winners[0], winners[1]);
+    }
+
+    #[test]
+    fn fog_of_war_hides_distant_units() {
+        let teams = [Team::Red, Team::Blue];
+        let input_ids = |walls_block| {
+            let settings = Settings {
+                fog_of_war: Some(FogOfWar {
+                    vision_radius: 5,
+                    walls_block,
+                }),
+                ..Default::default()
+            };
+            let mut state = State::new(settings, &teams, Some("fog")).unwrap();
+            let wall = Obj::new_terrain(state.ids.new_id(), TerrainType::Wall, Coords(8, 8));
+            state.grid.insert(wall.coords(), wall.id());
+            state.objs.insert(wall.id(), wall);
+            let units = [
+                (Team::Red, Coords(7, 9)),
+                (Team::Blue, Coords(9, 10)),
+                (Team::Blue, Coords(9, 7)),
+                (Team::Blue, Coords(13, 9)),
+            ];
+            let ids = units
+                .iter()
+                .map(|&(team, coords)| {
+                    let unit =
+                        Obj::new_unit(state.ids.new_id(), UnitType::Soldier, coords, team, 5);
+                    state.grid.insert(coords, unit.id());
+                    state.objs.insert(unit.id(), unit.clone());
+                    unit.id()
+                })
+                .collect::<Vec<_>>();
+            // the far away blue unit carries red's flag, and the red unit carries blue's
+            state.place_flags().unwrap();
+            for (team, carrier) in [(Team::Red, ids[3]), (Team::Blue, ids[0])] {
+                let flag = state.flags.get_mut(&team).unwrap();
+                flag.carrier = Some(carrier);
+                flag.coords = state.objs[&carrier].coords();
             }
-            grid.extend(legal_moves);
-            break;
-        } else {
-            // insert the units with illegal moves back in their original location
-            for (_, id) in illegal_moves.into_iter() {
-                grid.insert(objs.get(&id).unwrap().0.coords, id);
+            state.resources.insert(Team::Blue, 20);
+            let turn_state = TurnState { turn: 1, state };
+            let input = ProgramInput::new(&turn_state, &teams, Team::Red);
+            for (coords, id) in input.state.grid.iter() {
+                assert_eq!(input.state.objs[id].coords(), *coords);
             }
+            let hidden_flag = FlagForProgramInput {
+                base: Coords(2, 9),
+                coords: None,
+                carrier: None,
+            };
+            assert_eq!(input.flags[&Team::Red], hidden_flag);
+            assert_eq!(input.flags[&Team::Blue].carrier, Some(ids[0]));
+            assert_eq!(input.flags[&Team::Blue].coords, Some(Coords(7, 9)));
+            assert_eq!(input.resources.get(&Team::Blue), None);
+            (ids, input.state.teams[&Team::Blue].clone())
+        };
+
+        // the unit at (9, 7) is behind the wall, and the one at (13, 9) is too far away
+        let (ids, visible) = input_ids(true);
+        assert_eq!(visible, [ids[1]]);
+        let (ids, visible) = input_ids(false);
+        assert_eq!(visible, [ids[1], ids[2]]);
+    }
+
+    #[test]
+    fn flags_are_carried_captured_and_dropped() {
+        use Direction::*;
+        let (red, blue) = (Team::Red, Team::Blue);
+        let ctf = GameMode::CaptureTheFlag;
+        let mut state = State::new(Settings::default(), &[red, blue], Some("flags")).unwrap();
+        state.place_flags().unwrap();
+        assert_eq!(state.flags[&red].base, Coords(2, 9));
+        assert_eq!(state.flags[&blue].base, Coords(16, 9));
+
+        let act = |id, type_, direction| {
+            let action = Action { type_, direction };
+            vec![(id, Ok(Some(action)))].into_iter().collect()
+        };
+
+        let runner = add_soldier(&mut state, blue, Coords(3, 9), 5);
+        run_turn(&act(runner, ActionType::Move, West), &mut state, ctf);
+        assert_eq!(state.flags[&red].carrier, Some(runner));
+
+        // carry the flag most of the way home
+        state.grid.remove(&Coords(2, 9));
+        state.grid.insert(Coords(15, 9), runner);
+        state.objs.get_mut(&runner).unwrap().0.coords = Coords(15, 9);
+        run_turn(&act(runner, ActionType::Move, East), &mut state, ctf);
+        assert_eq!(state.captures[&blue], 1);
+        assert_eq!(
+            state.flags[&red],
+            Flag {
+                base: Coords(2, 9),
+                coords: Coords(2, 9),
+                carrier: None,
+            }
+        );
+
+        // the runner steps off its base as an enemy moves onto it
+        let thief = add_soldier(&mut state, red, Coords(15, 9), 1);
+        let mut actions = act(thief, ActionType::Move, East);
+        actions.extend(act(runner, ActionType::Move, South));
+        run_turn(&actions, &mut state, ctf);
+        assert_eq!(state.flags[&blue].carrier, Some(thief));
+        run_turn(&act(runner, ActionType::Attack, North), &mut state, ctf);
+        assert!(!state.objs.contains_key(&thief));
+        assert_eq!(state.flags[&blue].carrier, None);
+        assert_eq!(state.flags[&blue].coords, Coords(16, 9));
+    }
+
+    #[test]
+    fn flag_bases_must_be_separate_and_open() {
+        let place_flags = |settings| {
+            let mut state = State::new(settings, &[Team::Red, Team::Blue], Some("bases")).unwrap();
+            state.place_flags()
+        };
+        assert!(place_flags(Settings::default()).is_ok());
+        // on the smallest grid, the default base is the center, which every team would share
+        let settings = Settings {
+            grid_size: MIN_GRID_SIZE,
+            ..Default::default()
+        };
+        assert!(matches!(
+            place_flags(settings),
+            Err(SettingsError::InvalidFlagBase(Coords(2, 2)))
+        ));
+        let settings = Settings {
+            flag_base: Some(Coords(0, 9)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            place_flags(settings),
+            Err(SettingsError::InvalidFlagBase(Coords(0, 9)))
+        ));
+    }
+
+    fn add_soldier(state: &mut State, team: Team, coords: Coords, health: usize) -> Id {
+        let unit = Obj::new_unit(state.ids.new_id(), UnitType::Soldier, coords, team, health);
+        state.grid.insert(coords, unit.id());
+        state.objs.insert(unit.id(), unit.clone());
+        unit.id()
+    }
+
+    #[test]
+    fn flags_drop_where_their_carrier_dies() {
+        use Direction::*;
+        let (red, blue) = (Team::Red, Team::Blue);
+        let mut state = State::new(Settings::default(), &[red, blue], Some("drop")).unwrap();
+        state.place_flags().unwrap();
+        let carrier = add_soldier(&mut state, blue, Coords(5, 9), 1);
+        let follower = add_soldier(&mut state, blue, Coords(4, 9), 5);
+        let attacker = add_soldier(&mut state, red, Coords(7, 9), 5);
+        let flag = state.flags.get_mut(&red).unwrap();
+        flag.carrier = Some(carrier);
+        flag.coords = Coords(5, 9);
+
+        // the carrier steps into an attack, while a teammate moves onto the cell it left
+        let actions = [
+            (carrier, ActionType::Move, East),
+            (follower, ActionType::Move, East),
+            (attacker, ActionType::Attack, West),
+        ]
+        .iter()
+        .map(|&(id, type_, direction)| (id, Ok(Some(Action { type_, direction }))))
+        .collect();
+        run_turn(&actions, &mut state, GameMode::CaptureTheFlag);
+        assert!(!state.objs.contains_key(&carrier));
+        assert_eq!(state.flags[&red].coords, Coords(6, 9));
+        assert_eq!(state.flags[&red].carrier, None);
+    }
+
+    #[test]
+    fn flags_of_units_killed_by_the_zone_return_to_base() {
+        let (red, blue) = (Team::Red, Team::Blue);
+        let settings = Settings {
+            map_type: MapType::Rect,
+            shrink: ShrinkSettings {
+                start_turn: 1,
+                shrink_every: 1,
+                min_size: 15,
+                damage: None,
+            },
+            ..Default::default()
+        };
+        let mut state = State::new(settings, &[red, blue], Some("zone flags")).unwrap();
+        state.place_flags().unwrap();
+        state.start_zone();
+        let carrier = add_soldier(&mut state, blue, Coords(1, 5), 5);
+        let flag = state.flags.get_mut(&red).unwrap();
+        flag.carrier = Some(carrier);
+        flag.coords = Coords(1, 5);
+
+        state.close_zone(2, &mut BTreeMap::new());
+        assert!(!state.objs.contains_key(&carrier));
+        assert_eq!(
+            state.flags[&red],
+            Flag {
+                base: Coords(2, 9),
+                coords: Coords(2, 9),
+                carrier: None,
+            }
+        );
+    }
+
+    #[test]
+    fn resources_are_harvested_and_spent() {
+        use Direction::*;
+        let (red, blue) = (Team::Red, Team::Blue);
+        let settings = Settings {
+            economy: Some(EconomySettings {
+                starting_resources: 5,
+                harvest_amount: 3,
+            }),
+            ..Default::default()
+        };
+        let mut state = State::new(settings, &[red, blue], Some("economy")).unwrap();
+        let node = Obj::new_terrain(state.ids.new_id(), TerrainType::Resource, Coords(9, 8));
+        let harvester = Obj::new_unit(state.ids.new_id(), UnitType::Soldier, Coords(9, 9), red, 5);
+        for obj in [node, harvester.clone()] {
+            state.grid.insert(obj.coords(), obj.id());
+            state.objs.insert(obj.id(), obj);
+        }
+        let action = Action {
+            type_: ActionType::Harvest,
+            direction: North,
+        };
+        let actions = vec![(harvester.id(), Ok(Some(action)))]
+            .into_iter()
+            .collect();
+        run_turn(&actions, &mut state, GameMode::Normal);
+        assert_eq!(state.resources[&red], 8);
+
+        state.spawn_orders.insert(
+            red,
+            vec![UnitType::Archer, UnitType::Tank, UnitType::Soldier],
+        );
+        state.spawn_orders.insert(blue, vec![UnitType::Soldier; 2]);
+        let spawned = state.buy_units();
+        // red can't afford the tank, and doesn't skip ahead to the soldier
+        assert_eq!(spawned[&red], 1);
+        assert_eq!(spawned[&blue], 1);
+        assert_eq!(state.resources[&red], 2);
+        assert_eq!(state.resources[&blue], 0);
+        assert!(state.spawn_orders.is_empty());
+
+        let unit = match harvester.details() {
+            ObjDetails::Unit(unit) => unit,
+            _ => unreachable!(),
+        };
+        state.settings.economy = None;
+        assert!(state
+            .check_unit_action(unit, harvester.coords(), action, 1)
+            .is_err());
+    }
+
+    #[test]
+    fn economy_settings_are_validated() {
+        let settings = Settings {
+            spawn_settings: None,
+            economy: Some(EconomySettings::default()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            settings.validate(2),
+            Err(SettingsError::EconomyWithoutSpawns)
+        ));
+        let settings = Settings {
+            economy: Some(EconomySettings::default()),
+            ..Default::default()
+        };
+        assert!(settings.validate(2).is_ok());
+
+        // an overridden unit type doesn't become free
+        let mut stats = serde_json::to_value(UnitType::Tank.default_stats()).unwrap();
+        stats.as_object_mut().unwrap().remove("cost");
+        let table = serde_json::json!({ "Tank": stats });
+        assert!(serde_json::from_value::<UnitStatsTable>(table).is_err());
+    }
+
+    #[test]
+    fn zone_walls_off_rings() {
+        let red = Team::Red;
+        let settings = Settings {
+            map_type: MapType::Rect,
+            shrink: ShrinkSettings {
+                start_turn: 3,
+                shrink_every: 2,
+                min_size: 15,
+                damage: None,
+            },
+            ..Default::default()
+        };
+        let mut state = State::new(settings, &[red], Some("zone")).unwrap();
+        state.start_zone();
+        assert_eq!(state.zone.as_ref().unwrap().upcoming, vec![3, 5]);
+
+        let crushed = add_soldier(&mut state, red, Coords(1, 5), 5);
+        let safe = add_soldier(&mut state, red, Coords(2, 5), 5);
+        let mut wall = Obj::new_terrain(state.ids.new_id(), TerrainType::Wall, Coords(0, 3));
+        if let Obj(_, ObjDetails::Terrain(terrain)) = &mut wall {
+            terrain.health = Some(3);
         }
+        let node = Obj::new_terrain(state.ids.new_id(), TerrainType::Resource, Coords(1, 7));
+        for obj in [wall, node] {
+            state.grid.insert(obj.coords(), obj.id());
+            state.objs.insert(obj.id(), obj);
+        }
+        let mut stats = BTreeMap::new();
+        state.close_zone(2, &mut stats);
+        assert!(!state.grid.contains_key(&Coords(1, 9)));
+
+        state.close_zone(3, &mut stats);
+        assert!(state.objs.contains_key(&safe));
+        state.close_zone(5, &mut stats);
+        assert!(!state.objs.contains_key(&crushed));
+        assert_eq!(stats[&red].units_lost, 1);
+        assert_eq!(state.terrain_at(Coords(1, 5)), None);
+        assert!(state.zone.as_ref().unwrap().upcoming.is_empty());
+        // the destructible wall and the resource node are replaced too
+        for coords in [
+            Coords(0, 3),
+            Coords(1, 5),
+            Coords(1, 7),
+            Coords(1, 9),
+            Coords(17, 17),
+        ] {
+            let id = state.grid[&coords];
+            assert_eq!(
+                state.objs[&id].details(),
+                &ObjDetails::Terrain(Terrain {
+                    type_: TerrainType::Wall,
+                    health: None,
+                })
+            );
+        }
+        assert!(!state.grid.contains_key(&Coords(2, 9)));
+    }
+
+    #[test]
+    fn zone_damage_hurts_the_next_ring() {
+        let red = Team::Red;
+        let settings = Settings {
+            map_type: MapType::Rect,
+            shrink: ShrinkSettings {
+                start_turn: 3,
+                shrink_every: 2,
+                min_size: 13,
+                damage: Some(2),
+            },
+            ..Default::default()
+        };
+        let mut state = State::new(settings, &[red], Some("zone")).unwrap();
+        state.start_zone();
+        assert_eq!(state.zone.as_ref().unwrap().upcoming, vec![3, 5, 7]);
+        let ids = [Coords(1, 5), Coords(2, 5), Coords(3, 5)]
+            .iter()
+            .map(|&coords| add_soldier(&mut state, red, coords, 5))
+            .collect::<Vec<_>>();
+        let mut stats = BTreeMap::new();
+
+        // nothing hurts before the first ring closes
+        state.close_zone(2, &mut stats);
+        assert_eq!(health_of(&state, ids[0]), Some(5));
+        state.close_zone(3, &mut stats);
+        state.close_zone(4, &mut stats);
+        assert_eq!(health_of(&state, ids[0]), Some(1));
+        assert_eq!(health_of(&state, ids[1]), Some(5));
+
+        // the wall goes up on a ring as it closes, whatever the health of the units on it
+        state.close_zone(5, &mut stats);
+        assert_eq!(health_of(&state, ids[0]), None);
+        assert_eq!(health_of(&state, ids[1]), Some(3));
+        let wall = state.grid[&Coords(1, 5)];
+        assert!(matches!(
+            state.objs[&wall].details(),
+            ObjDetails::Terrain(_)
+        ));
+
+        // once the last ring has closed, there's no next one to hurt
+        state.close_zone(6, &mut stats);
+        state.close_zone(7, &mut stats);
+        state.close_zone(8, &mut stats);
+        assert_eq!(health_of(&state, ids[1]), None);
+        assert_eq!(health_of(&state, ids[2]), Some(5));
+        assert_eq!(stats[&red].damage_received, 10);
+        assert_eq!(stats[&red].units_lost, 2);
+    }
+
+    #[test]
+    fn walls_with_health_can_be_destroyed() {
+        use Direction::*;
+        let red = Team::Red;
+        let settings = Settings {
+            wall_health: vec![(TerrainType::Wall, 2)].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut state = State::new(settings, &[red], Some("walls")).unwrap();
+        let wall = state.grid[&Coords(0, 9)];
+        let unit = Obj::new_unit(state.ids.new_id(), UnitType::Soldier, Coords(1, 9), red, 5);
+        state.grid.insert(unit.coords(), unit.id());
+        state.objs.insert(unit.id(), unit.clone());
+        let act = |type_| {
+            let action = Action {
+                type_,
+                direction: West,
+            };
+            vec![(unit.id(), Ok(Some(action)))].into_iter().collect()
+        };
+
+        let before = state.objs.clone();
+        run_turn(&act(ActionType::Attack), &mut state, GameMode::Normal);
+        let diff = replay::ObjDiff::between(&before, &state.objs);
+        assert_eq!(diff.health, vec![(wall, 1)].into_iter().collect());
+        let mut replayed = before;
+        diff.apply(&mut replayed);
+        assert_eq!(replayed, state.objs);
+
+        run_turn(&act(ActionType::Attack), &mut state, GameMode::Normal);
+        assert!(!state.objs.contains_key(&wall));
+        run_turn(&act(ActionType::Move), &mut state, GameMode::Normal);
+        assert_eq!(state.grid.get(&Coords(0, 9)), Some(&unit.id()));
+    }
+
+    #[test]
+    fn wall_health_is_validated() {
+        let validate = |type_, health| {
+            let settings = Settings {
+                wall_health: vec![(type_, health)].into_iter().collect(),
+                ..Default::default()
+            };
+            settings.validate(2)
+        };
+        assert!(validate(TerrainType::Wall, 3).is_ok());
+        assert!(validate(TerrainType::Resource, 1).is_ok());
+        for (type_, health) in [
+            (TerrainType::Wall, 0),
+            (TerrainType::Swamp, 3),
+            (TerrainType::Cover, 3),
+        ] {
+            assert!(matches!(
+                validate(type_, health),
+                Err(SettingsError::InvalidWallHealth(t)) if t == type_
+            ));
+        }
+    }
+
+    #[test]
+    fn diagonals_depend_on_the_direction_set() {
+        let red = Team::Red;
+        let mut state = State::new(Settings::default(), &[red], Some("diagonals")).unwrap();
+        let unit = add_soldier(&mut state, red, Coords(9, 9), 5);
+        let mut turn_state = TurnState { turn: 1, state };
+        let action = Ok(Some(Action {
+            type_: ActionType::Move,
+            direction: Direction::NorthEast,
+        }));
+        assert!(matches!(
+            validate_robot_action(action.clone(), red, unit, &turn_state),
+            Err(RobotErrorAfterValidation::InvalidAction(_))
+        ));
+
+        turn_state.state.settings.directions = DirectionSet::Eight;
+        assert!(validate_robot_action(action.clone(), red, unit, &turn_state).is_ok());
+        let actions = vec![(unit, validate_robot_action(action, red, unit, &turn_state))]
+            .into_iter()
+            .collect();
+        run_turn(&actions, &mut turn_state.state, GameMode::Normal);
+        assert_eq!(turn_state.state.objs[&unit].coords(), Coords(10, 8));
+    }
+
+    fn add_terrain(state: &mut State, type_: TerrainType, coords: Coords) {
+        let terrain = Obj::new_terrain(state.ids.new_id(), type_, coords);
+        state.terrain_grid.insert(coords, terrain.id());
+        state.objs.insert(terrain.id(), terrain);
+    }
+
+    fn health_of(state: &State, id: Id) -> Option<usize> {
+        match state.objs.get(&id)?.details() {
+            ObjDetails::Unit(unit) => Some(unit.health),
+            ObjDetails::Terrain(_) => None,
+        }
+    }
+
+    #[test]
+    fn swamps_slow_units_down() {
+        let red = Team::Red;
+        let mut state = State::new(Settings::default(), &[red], Some("swamp")).unwrap();
+        add_terrain(&mut state, TerrainType::Swamp, Coords(9, 9));
+        let unit = add_soldier(&mut state, red, Coords(9, 9), 5);
+        let action = Action {
+            type_: ActionType::Move,
+            direction: Direction::North,
+        };
+        let check = |turn| match state.objs[&unit].details() {
+            ObjDetails::Unit(unit) => state.check_unit_action(unit, Coords(9, 9), action, turn),
+            _ => unreachable!(),
+        };
+        assert_eq!(check(1), Ok(()));
+        assert!(check(2).is_err());
+        assert_eq!(check(3), Ok(()));
+    }
+
+    #[test]
+    fn fountains_heal_up_to_max_health() {
+        let red = Team::Red;
+        let mut state = State::new(Settings::default(), &[red], Some("fountain")).unwrap();
+        add_terrain(&mut state, TerrainType::Fountain, Coords(9, 9));
+        let unit = add_soldier(&mut state, red, Coords(9, 9), 4);
+        run_turn(&BTreeMap::new(), &mut state, GameMode::Normal);
+        assert_eq!(health_of(&state, unit), Some(5));
+        run_turn(&BTreeMap::new(), &mut state, GameMode::Normal);
+        assert_eq!(health_of(&state, unit), Some(5));
+    }
+
+    #[test]
+    fn lava_damages_and_kills() {
+        let red = Team::Red;
+        let mut state = State::new(Settings::default(), &[red], Some("lava")).unwrap();
+        add_terrain(&mut state, TerrainType::Lava, Coords(9, 9));
+        let unit = add_soldier(&mut state, red, Coords(9, 9), 2);
+        run_turn(&BTreeMap::new(), &mut state, GameMode::Normal);
+        assert_eq!(health_of(&state, unit), Some(1));
+        let events = run_turn(&BTreeMap::new(), &mut state, GameMode::Normal);
+        assert_eq!(health_of(&state, unit), None);
+        assert!(!state.grid.contains_key(&Coords(9, 9)));
+        assert_eq!(events.stats[&red].units_lost, 1);
+    }
+
+    #[test]
+    fn cover_absorbs_damage() {
+        let (red, blue) = (Team::Red, Team::Blue);
+        let mut state = State::new(Settings::default(), &[red, blue], Some("cover")).unwrap();
+        add_terrain(&mut state, TerrainType::Cover, Coords(9, 9));
+        let covered = add_soldier(&mut state, red, Coords(9, 9), 5);
+        let exposed = add_soldier(&mut state, red, Coords(5, 5), 5);
+        let attacks = [
+            (Coords(8, 9), Direction::East),
+            (Coords(10, 9), Direction::West),
+            (Coords(5, 4), Direction::South),
+            (Coords(5, 6), Direction::North),
+        ];
+        let actions = attacks
+            .iter()
+            .map(|&(coords, direction)| {
+                let attacker = add_soldier(&mut state, blue, coords, 5);
+                let action = Action {
+                    type_: ActionType::Attack,
+                    direction,
+                };
+                (attacker, Ok(Some(action)))
+            })
+            .collect();
+        run_turn(&actions, &mut state, GameMode::Normal);
+        // cover only takes off the first point of all the attacks together
+        assert_eq!(health_of(&state, covered), Some(4));
+        assert_eq!(health_of(&state, exposed), Some(3));
     }
 }
@@ -0,0 +1,215 @@
+//! A plain-text map format, with one character per cell:
+//!
+//! - `#`: a wall
+//! - `.`: an empty floor cell
+//! - `S`: a spawn point
+//! - `H`: a hill cell
+//! - `~`, `+`, `^`, `=`: a swamp, fountain, lava or cover cell (see `TerrainType`)
+//! - `$`: a resource node, for `Settings::economy`
+//!
+//! Every line is a row of the grid (top to bottom, so line `y + 1` holds the cells with y-coordinate
+//! `y`), and the map must be square.
+
+use thiserror::Error;
+
+use crate::{
+    Coords, GridInitType, InitObj, MapType, ObjDetails, Settings, State, Terrain, TerrainType,
+};
+
+const FLOOR: char = '.';
+const SPAWN: char = 'S';
+const HILL: char = 'H';
+const TERRAIN: [(char, TerrainType); 6] = [
+    ('#', TerrainType::Wall),
+    ('~', TerrainType::Swamp),
+    ('+', TerrainType::Fountain),
+    ('^', TerrainType::Lava),
+    ('=', TerrainType::Cover),
+    ('$', TerrainType::Resource),
+];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MapParseError {
+    #[error("The map is empty")]
+    Empty,
+    #[error("Unexpected character {ch:?} at line {line}, column {column}")]
+    UnexpectedChar {
+        ch: char,
+        line: usize,
+        column: usize,
+    },
+    #[error("Line {line} has {found} cells, expected {expected} (at column {column})")]
+    RaggedLine {
+        line: usize,
+        column: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[error("The map has {width} columns but {height} lines; it must be square")]
+    NotSquare { width: usize, height: usize },
+    #[error("The map has no spawn points")]
+    NoSpawnPoints,
+}
+
+/// Parses a text map into `Settings` with `MapType::Custom`, with the terrain in `grid_init` and the
+/// spawn points and hill set explicitly. All other settings are left at their defaults.
+pub fn parse(text: &str) -> Result<Settings, MapParseError> {
+    let lines = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect::<Vec<_>>();
+    // ignore trailing blank lines, e.g. from a final newline in the file
+    let height = lines
+        .iter()
+        .rposition(|line| !line.is_empty())
+        .map_or(0, |i| i + 1);
+    let lines = &lines[..height];
+
+    let width = lines.first().ok_or(MapParseError::Empty)?.chars().count();
+
+    let mut grid_init = GridInitType::new();
+    let mut spawn_points = Vec::new();
+    let mut hill = Vec::new();
+    for (y, line) in lines.iter().enumerate() {
+        let mut found = 0;
+        for (x, ch) in line.chars().enumerate() {
+            let coords = Coords(x, y);
+            match ch {
+                FLOOR => {}
+                SPAWN => spawn_points.push(coords),
+                HILL => hill.push(coords),
+                _ => match TERRAIN.iter().find(|(terrain_ch, _)| *terrain_ch == ch) {
+                    Some(&(_, type_)) => {
+                        let terrain = Terrain {
+                            type_,
+                            health: None,
+                        };
+                        grid_init.push(InitObj(coords, ObjDetails::Terrain(terrain)))
+                    }
+                    None => {
+                        return Err(MapParseError::UnexpectedChar {
+                            ch,
+                            line: y + 1,
+                            column: x + 1,
+                        })
+                    }
+                },
+            }
+            found += 1;
+        }
+        if found != width {
+            return Err(MapParseError::RaggedLine {
+                line: y + 1,
+                column: usize::min(found, width) + 1,
+                expected: width,
+                found,
+            });
+        }
+    }
+
+    if width != height {
+        return Err(MapParseError::NotSquare { width, height });
+    }
+    if spawn_points.is_empty() {
+        return Err(MapParseError::NoSpawnPoints);
+    }
+
+    Ok(Settings {
+        grid_init,
+        grid_size: width,
+        map_type: MapType::Custom,
+        spawn_points: Some(spawn_points),
+        hill: if hill.is_empty() { None } else { Some(hill) },
+        ..Default::default()
+    })
+}
+
+/// Prints the map of `state` in the text format. Units aren't part of the format, so the cells
+/// they stand on are printed as whatever is underneath them. A cell only holds one character, so
+/// terrain takes precedence over spawn points, which take precedence over the hill. The hill is
+/// only printed if the settings define one, rather than using the default.
+pub fn print(state: &State) -> String {
+    let size = state.settings.grid_size;
+    let mut cells = vec![vec![FLOOR; size]; size];
+
+    for &Coords(x, y) in state.settings.hill.iter().flatten() {
+        cells[y][x] = HILL;
+    }
+    for &Coords(x, y) in &state.spawn_points {
+        cells[y][x] = SPAWN;
+    }
+    for obj in state.objs.values() {
+        if let ObjDetails::Terrain(terrain) = obj.details() {
+            let Coords(x, y) = obj.coords();
+            if let Some(&(ch, _)) = TERRAIN.iter().find(|(_, type_)| *type_ == terrain.type_) {
+                cells[y][x] = ch;
+            }
+        }
+    }
+
+    let mut out = String::with_capacity(size * (size + 1));
+    for row in cells {
+        out.extend(row);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Team;
+
+    const MAP: &str = "\
+#######
+#S...~#
+#.=.+.#
+#..H..#
+#.^.$.#
+#~...S#
+#######
+";
+
+    #[test]
+    fn maps_round_trip() {
+        let settings = parse(MAP).unwrap();
+        assert_eq!(
+            settings.spawn_points,
+            Some(vec![Coords(1, 1), Coords(5, 5)])
+        );
+        assert_eq!(settings.hill, Some(vec![Coords(3, 3)]));
+        let state = State::new(settings, &[Team::Red, Team::Blue], Some("map")).unwrap();
+        assert_eq!(print(&state), MAP);
+
+        // without a hill in the map, none is printed
+        let map = MAP.replace('H', ".");
+        let state = State::new(parse(&map).unwrap(), &[Team::Red], Some("map")).unwrap();
+        assert_eq!(print(&state), map);
+    }
+
+    #[test]
+    fn errors_point_at_the_cell() {
+        assert_eq!(
+            parse(&MAP.replacen('=', "x", 1)).unwrap_err(),
+            MapParseError::UnexpectedChar {
+                ch: 'x',
+                line: 3,
+                column: 3,
+            }
+        );
+        assert_eq!(
+            parse(&MAP.replacen("#.^.$.#", "#.^.$#", 1)).unwrap_err(),
+            MapParseError::RaggedLine {
+                line: 5,
+                column: 7,
+                expected: 7,
+                found: 6,
+            }
+        );
+        assert_eq!(
+            parse(&MAP.replace('S', ".")).unwrap_err(),
+            MapParseError::NoSpawnPoints
+        );
+        assert_eq!(parse("\n\n").unwrap_err(), MapParseError::Empty);
+    }
+}
@@ -0,0 +1,135 @@
+//! A MessagePack encoding of the types that otherwise go through `serde_json`, e.g. `MainOutput`
+//! (or `replay::DeltaReplay`) for storing replays, and `ProgramInput`/`ProgramOutput` for talking
+//! to runners.
+//!
+//! Structs are encoded as maps with their field names rather than as arrays, since the flattened
+//! and internally tagged representations used by `Obj` and `ProgramInput` need the field names to
+//! be read back.
+
+use serde::{Deserialize, Serialize};
+
+pub use rmp_serde::{decode::Error as DecodeError, encode::Error as EncodeError};
+
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    rmp_serde::to_vec_named(value)
+}
+
+pub fn from_slice<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, DecodeError> {
+    rmp_serde::from_slice(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn play() -> Game {
+        let mut game = Game::new(MatchConfig {
+            teams: vec![Team::Red, Team::Blue],
+            max_turn: 10,
+            dev_mode: true,
+            settings: Settings::default(),
+            game_mode: GameMode::Normal,
+            seed: Some("robot-rumble".to_owned()),
+        })
+        .unwrap();
+        while !game.is_finished() {
+            for team in [Team::Red, Team::Blue] {
+                let ids = game.current_input(team).state.teams[&team].clone();
+                let direction = if team == Team::Red {
+                    Direction::East
+                } else {
+                    Direction::West
+                };
+                let output = ProgramOutput {
+                    robot_actions: ids
+                        .iter()
+                        .map(|&id| {
+                            let type_ = if game.turn() % 2 == 1 {
+                                ActionType::Move
+                            } else {
+                                ActionType::Attack
+                            };
+                            (id, Ok(Some(Action { type_, direction })))
+                        })
+                        .collect(),
+                    logs: vec![format!("turn {}", game.turn())],
+                    debug_inspect_tables: BTreeMap::new(),
+                    debug_locate_queries: ids,
+                    spawn_order: None,
+                };
+                game.submit(team, output);
+            }
+            game.step();
+        }
+        game
+    }
+
+    /// Compares through `serde_json`, since not all of the types implement `PartialEq`
+    fn assert_round_trips<T>(value: &T)
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        let decoded: T = from_slice(&to_vec(value).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::to_value(value).unwrap(),
+            serde_json::to_value(&decoded).unwrap()
+        );
+    }
+
+    #[test]
+    fn main_output_round_trips() {
+        let output = play().finish();
+        assert_round_trips(&output);
+        assert_round_trips(&replay::DeltaReplay::from(output));
+    }
+
+    #[test]
+    fn program_io_round_trips() {
+        let game = play();
+        let input = game.current_input(Team::Blue);
+        let decoded: ProgramInput = from_slice(&to_vec(&input).unwrap()).unwrap();
+        assert_eq!(*decoded.state.objs, *input.state.objs);
+        assert_eq!(**decoded.state.grid, **input.state.grid);
+        assert_eq!(decoded.state.teams, input.state.teams);
+        assert_eq!(decoded.grid_size, input.grid_size);
+        assert_eq!(*decoded.spawn_points, *input.spawn_points);
+        assert_eq!(decoded.hill, input.hill);
+
+        let output = ProgramOutput {
+            robot_actions: vec![
+                (Id(1), Ok(None)),
+                (
+                    Id(2),
+                    Ok(Some(Action {
+                        type_: ActionType::Heal,
+                        direction: Direction::South,
+                    })),
+                ),
+                (
+                    Id(3),
+                    Err(Error {
+                        summary: "oops".to_owned(),
+                        details: None,
+                        loc: Some(ErrorLoc {
+                            start: (1, Some(2)),
+                            end: None,
+                        }),
+                    }),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            logs: vec!["hello".to_owned()],
+            debug_inspect_tables: vec![(
+                Id(1),
+                vec![("a".to_owned(), "b".to_owned())].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            debug_locate_queries: vec![Id(2)],
+            spawn_order: Some(vec![UnitType::Tank, UnitType::Medic]),
+        };
+        assert_round_trips(&output);
+    }
+}
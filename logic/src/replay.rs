@@ -0,0 +1,527 @@
+//! Checking recorded matches against the rules of the game, and a compact format for storing them.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    CallbackInput, Coords, DebugTable, Flag, Game, Id, MainOutput, MatchConfig, MoveConflict, Obj,
+    ObjDetails, ObjMap, ProgramError, Settings, SettingsError, StateForOutput, Team, TeamStats,
+    Termination, Terrain, UnitType, ValidatedRobotAction, Zone,
+};
+
+#[derive(Error, Debug, Clone)]
+pub enum VerifyError {
+    #[error("The match settings are invalid: {0}")]
+    Settings(#[from] SettingsError),
+    #[error("Turn {turn}: the recorded state doesn't match the recomputed one")]
+    StateMismatch { turn: usize },
+    #[error("Turn {turn}: an action that isn't allowed was recorded for object {}", .id.0)]
+    InvalidAction { turn: usize, id: Id },
+    #[error("The match should have {expected} turns, but {found} were recorded")]
+    TurnCount { expected: usize, found: usize },
+    #[error("The recorded winner doesn't match the recomputed one")]
+    WinnerMismatch,
+    #[error("The recorded reason for the match ending doesn't match the recomputed one")]
+    TerminationMismatch,
+    #[error("The recorded match statistics don't match the recomputed ones")]
+    StatsMismatch,
+}
+
+/// Replays `output` from the start under `config`, applying the actions that were recorded for
+/// each turn, and checks that every recorded state, the winner and the statistics match what the
+/// rules produce. `config` must be the one the match was played with. Unit spawns and ids depend
+/// on the seed, so the one recorded in `output` is used in place of `config.seed`, if there is one.
+///
+/// Returns the first inconsistency found. Logs and debug output aren't checked.
+pub fn verify(output: &MainOutput, config: &MatchConfig) -> Result<(), VerifyError> {
+    let mut config = config.clone();
+    if output.seed.is_some() {
+        config.seed = output.seed.clone();
+    }
+    let mut game = Game::new(config.clone())?;
+
+    let mut turns = output.turns.iter();
+    while !game.is_finished() {
+        let recorded = match turns.next() {
+            Some(recorded) => recorded,
+            // the programs errored, so the match was cut short
+            None if !output.errors.is_empty() => break,
+            None => {
+                return Err(VerifyError::TurnCount {
+                    expected: config.max_turn + 1,
+                    found: output.turns.len(),
+                })
+            }
+        };
+        let turn = game.turn();
+        if recorded.state.turn != turn || recorded.state.objs != game.state().objs {
+            return Err(VerifyError::StateMismatch { turn });
+        }
+        for (&id, action) in &recorded.robot_actions {
+            let state = game.state();
+            let is_valid = match (action, state.objs.get(&id)) {
+                (Err(_), _) => true,
+                (Ok(action), Some(Obj(basic, ObjDetails::Unit(unit)))) => {
+                    action.map_or(Ok(()), |action| {
+                        state.check_unit_action(unit, basic.coords, action, turn)
+                    }) == Ok(())
+                }
+                _ => false,
+            };
+            if !is_valid {
+                return Err(VerifyError::InvalidAction { turn, id });
+            }
+        }
+        game.submit_validated(
+            recorded.robot_actions.clone(),
+            recorded.spawn_orders.clone(),
+        );
+        game.step();
+    }
+
+    let recomputed = if output.errors.is_empty() {
+        let turn = game.turn();
+        let recorded = turns.next().ok_or(VerifyError::TurnCount {
+            expected: config.max_turn + 1,
+            found: output.turns.len(),
+        })?;
+        if recorded.state.turn != turn || recorded.state.objs != game.state().objs {
+            return Err(VerifyError::StateMismatch { turn });
+        }
+        game.finish()
+    } else {
+        game.finish_with_errors(output.errors.clone())
+    };
+
+    if recomputed.turns.len() != output.turns.len() {
+        return Err(VerifyError::TurnCount {
+            expected: recomputed.turns.len(),
+            found: output.turns.len(),
+        });
+    }
+    if recomputed.winner != output.winner {
+        return Err(VerifyError::WinnerMismatch);
+    }
+    if recomputed.termination != output.termination {
+        return Err(VerifyError::TerminationMismatch);
+    }
+    if recomputed.stats != output.stats {
+        return Err(VerifyError::StatsMismatch);
+    }
+    Ok(())
+}
+
+/// How the objects on the board changed from one turn to the next.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ObjDiff {
+    /// The new coordinates of objects that moved
+    pub moved: BTreeMap<Id, Coords>,
+    /// The new health of units that were damaged or healed, and of walls that were damaged
+    pub health: BTreeMap<Id, usize>,
+    pub died: Vec<Id>,
+    /// New objects. An object that changed in any other way is recorded as dying and being
+    /// spawned again with the same id.
+    pub spawned: Vec<Obj>,
+}
+
+impl ObjDiff {
+    pub fn between(old: &ObjMap, new: &ObjMap) -> Self {
+        let mut diff = Self::default();
+        for (&id, Obj(old_basic, old_details)) in old {
+            let new_obj = match new.get(&id) {
+                Some(obj) => obj,
+                None => {
+                    diff.died.push(id);
+                    continue;
+                }
+            };
+            let Obj(new_basic, new_details) = new_obj;
+            match (old_details, new_details) {
+                (ObjDetails::Unit(old_unit), ObjDetails::Unit(new_unit))
+                    if old_unit.type_ == new_unit.type_ && old_unit.team == new_unit.team =>
+                {
+                    if old_unit.health != new_unit.health {
+                        diff.health.insert(id, new_unit.health);
+                    }
+                }
+                (old_details, new_details) if old_details == new_details => {}
+                (
+                    ObjDetails::Terrain(old_terrain),
+                    ObjDetails::Terrain(Terrain {
+                        type_,
+                        health: Some(health),
+                    }),
+                ) if old_terrain.type_ == *type_ && old_terrain.health.is_some() => {
+                    diff.health.insert(id, *health);
+                }
+                _ => {
+                    diff.died.push(id);
+                    diff.spawned.push(new_obj.clone());
+                    continue;
+                }
+            }
+            if old_basic.coords != new_basic.coords {
+                diff.moved.insert(id, new_basic.coords);
+            }
+        }
+        diff.spawned.extend(
+            new.iter()
+                .filter(|(id, _)| !old.contains_key(id))
+                .map(|(_, obj)| obj.clone()),
+        );
+        diff
+    }
+
+    pub fn apply(&self, objs: &mut ObjMap) {
+        for id in &self.died {
+            objs.remove(id);
+        }
+        for obj in &self.spawned {
+            objs.insert(obj.id(), obj.clone());
+        }
+        for (id, &coords) in &self.moved {
+            if let Some(Obj(basic, _)) = objs.get_mut(id) {
+                basic.coords = coords;
+            }
+        }
+        for (id, &health) in &self.health {
+            match objs.get_mut(id) {
+                Some(Obj(_, ObjDetails::Unit(unit))) => unit.health = health,
+                Some(Obj(_, ObjDetails::Terrain(terrain))) => terrain.health = Some(health),
+                None => {}
+            }
+        }
+    }
+}
+
+/// A `CallbackInput` with the objects stored as changes from the turn before.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TurnDelta {
+    pub turn: usize,
+    pub changes: ObjDiff,
+    pub robot_actions: BTreeMap<Id, ValidatedRobotAction>,
+
+    pub logs: BTreeMap<Team, Vec<String>>,
+    pub debug_inspect_tables: BTreeMap<Id, DebugTable>,
+    pub debug_locate_queries: BTreeMap<Team, Vec<Id>>,
+    #[serde(default)]
+    pub move_conflicts: Vec<MoveConflict>,
+    #[serde(default)]
+    pub visible_units: BTreeMap<Team, Vec<Id>>,
+    #[serde(default)]
+    pub hill_score: BTreeMap<Team, usize>,
+    #[serde(default)]
+    pub flags: BTreeMap<Team, Flag>,
+    #[serde(default)]
+    pub captures: BTreeMap<Team, usize>,
+    #[serde(default)]
+    pub resources: BTreeMap<Team, usize>,
+    #[serde(default)]
+    pub spawn_orders: BTreeMap<Team, Vec<UnitType>>,
+    #[serde(default)]
+    pub zone: Option<Zone>,
+}
+
+/// A `MainOutput` that stores the objects of the first turn in full, and after that only how
+/// they change each turn. Converting to and from `MainOutput` is lossless.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeltaReplay {
+    pub winner: Option<Team>,
+    pub errors: BTreeMap<Team, ProgramError>,
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub seed: Option<String>,
+    #[serde(default)]
+    pub termination: Termination,
+    #[serde(default)]
+    pub stats: BTreeMap<Team, TeamStats>,
+    pub initial_objs: ObjMap,
+    pub turns: Vec<TurnDelta>,
+}
+
+impl From<MainOutput> for DeltaReplay {
+    fn from(output: MainOutput) -> Self {
+        let initial_objs = output
+            .turns
+            .first()
+            .map(|turn| turn.state.objs.clone())
+            .unwrap_or_default();
+
+        let mut prev_objs = &initial_objs;
+        let mut turns = Vec::with_capacity(output.turns.len());
+        for turn in &output.turns {
+            turns.push(TurnDelta {
+                turn: turn.state.turn,
+                changes: ObjDiff::between(prev_objs, &turn.state.objs),
+                robot_actions: turn.robot_actions.clone(),
+                logs: turn.logs.clone(),
+                debug_inspect_tables: turn.debug_inspect_tables.clone(),
+                debug_locate_queries: turn.debug_locate_queries.clone(),
+                move_conflicts: turn.move_conflicts.clone(),
+                visible_units: turn.visible_units.clone(),
+                hill_score: turn.hill_score.clone(),
+                flags: turn.flags.clone(),
+                captures: turn.captures.clone(),
+                resources: turn.resources.clone(),
+                spawn_orders: turn.spawn_orders.clone(),
+                zone: turn.zone.clone(),
+            });
+            prev_objs = &turn.state.objs;
+        }
+
+        Self {
+            winner: output.winner,
+            errors: output.errors,
+            settings: output.settings,
+            seed: output.seed,
+            termination: output.termination,
+            stats: output.stats,
+            initial_objs,
+            turns,
+        }
+    }
+}
+
+impl From<DeltaReplay> for MainOutput {
+    fn from(replay: DeltaReplay) -> Self {
+        let mut objs = replay.initial_objs;
+        let turns = replay
+            .turns
+            .into_iter()
+            .map(|turn| {
+                turn.changes.apply(&mut objs);
+                CallbackInput {
+                    state: StateForOutput {
+                        objs: objs.clone(),
+                        turn: turn.turn,
+                    },
+                    robot_actions: turn.robot_actions,
+                    logs: turn.logs,
+                    debug_inspect_tables: turn.debug_inspect_tables,
+                    debug_locate_queries: turn.debug_locate_queries,
+                    move_conflicts: turn.move_conflicts,
+                    visible_units: turn.visible_units,
+                    hill_score: turn.hill_score,
+                    flags: turn.flags,
+                    captures: turn.captures,
+                    resources: turn.resources,
+                    spawn_orders: turn.spawn_orders,
+                    zone: turn.zone,
+                }
+            })
+            .collect();
+
+        Self {
+            winner: replay.winner,
+            errors: replay.errors,
+            turns,
+            settings: replay.settings,
+            seed: replay.seed,
+            termination: replay.termination,
+            stats: replay.stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn config() -> MatchConfig {
+        // a line of destructible walls down the middle, which the teams have to break through
+        let grid_init = (3..16)
+            .map(|y| {
+                let terrain = Terrain {
+                    type_: TerrainType::Wall,
+                    health: None,
+                };
+                InitObj(Coords(9, y), ObjDetails::Terrain(terrain))
+            })
+            .collect();
+        MatchConfig {
+            teams: vec![Team::Red, Team::Blue],
+            max_turn: 40,
+            dev_mode: false,
+            settings: Settings {
+                grid_init,
+                wall_health: vec![(TerrainType::Wall, 2)].into_iter().collect(),
+                shrink: ShrinkSettings {
+                    start_turn: 25,
+                    shrink_every: 5,
+                    min_size: 11,
+                    damage: None,
+                },
+                ..Default::default()
+            },
+            game_mode: GameMode::BattleRoyale,
+            seed: None,
+        }
+    }
+
+    /// Every unit heads for the other side of the grid, and attacks whatever is in its way
+    fn play(config: MatchConfig) -> MainOutput {
+        let mut game = Game::new(config).unwrap();
+        while !game.is_finished() {
+            for team in [Team::Red, Team::Blue] {
+                let direction = if team == Team::Red {
+                    Direction::East
+                } else {
+                    Direction::West
+                };
+                let state = game.state();
+                let robot_actions = state
+                    .objs
+                    .values()
+                    .filter(|obj| match obj.details() {
+                        ObjDetails::Unit(unit) => unit.team == team,
+                        _ => false,
+                    })
+                    .map(|obj| {
+                        let type_ = if state.grid.contains_key(&(obj.coords() + direction)) {
+                            ActionType::Attack
+                        } else {
+                            ActionType::Move
+                        };
+                        (obj.id(), Ok(Some(Action { type_, direction })))
+                    })
+                    .collect();
+                game.submit(team, program_output(robot_actions));
+            }
+            game.step();
+        }
+        game.finish()
+    }
+
+    fn program_output(robot_actions: BTreeMap<Id, ActionResult>) -> ProgramOutput {
+        ProgramOutput {
+            robot_actions,
+            logs: Vec::new(),
+            debug_inspect_tables: BTreeMap::new(),
+            debug_locate_queries: Vec::new(),
+            spawn_order: None,
+        }
+    }
+
+    #[test]
+    fn played_matches_verify() {
+        let output = play(config());
+        assert!(output.seed.is_some());
+        verify(&output, &config()).unwrap();
+    }
+
+    #[test]
+    fn matches_with_invalid_actions_verify() {
+        let mut game = Game::new(config()).unwrap();
+        while !game.is_finished() {
+            // red has every unit harvest without an economy, including blue's, which is ignored
+            let harvest = Action {
+                type_: ActionType::Harvest,
+                direction: Direction::North,
+            };
+            let robot_actions = game
+                .state()
+                .objs
+                .values()
+                .filter(|obj| matches!(obj.details(), ObjDetails::Unit(_)))
+                .map(|obj| (obj.id(), Ok(Some(harvest))))
+                .collect();
+            game.submit(Team::Red, program_output(robot_actions));
+            game.step();
+        }
+        let output = game.finish();
+        assert!(output.stats[&Team::Red].invalid_actions > 0);
+        assert_eq!(output.stats[&Team::Blue].invalid_actions, 0);
+        verify(&output, &config()).unwrap();
+    }
+
+    #[test]
+    fn tampered_matches_dont_verify() {
+        let output = play(config());
+        let tamper = |change: &dyn Fn(&mut MainOutput)| {
+            let mut output =
+                serde_json::from_value(serde_json::to_value(&output).unwrap()).unwrap();
+            change(&mut output);
+            verify(&output, &config())
+        };
+
+        let result = tamper(&|output| {
+            let objs = &mut output.turns[5].state.objs;
+            let unit = objs.values_mut().find_map(|obj| match obj {
+                Obj(_, ObjDetails::Unit(unit)) => Some(unit),
+                _ => None,
+            });
+            unit.unwrap().health -= 1;
+        });
+        assert!(matches!(
+            result,
+            Err(VerifyError::StateMismatch { turn: 6 })
+        ));
+
+        let result = tamper(&|output| {
+            let turn = &mut output.turns[2];
+            let wall = turn.state.objs.values().find(|obj| match obj.details() {
+                ObjDetails::Terrain(terrain) => terrain.type_ == TerrainType::Wall,
+                _ => false,
+            });
+            let action = Action {
+                type_: ActionType::Move,
+                direction: Direction::North,
+            };
+            turn.robot_actions
+                .insert(wall.unwrap().id(), Ok(Some(action)));
+        });
+        assert!(matches!(
+            result,
+            Err(VerifyError::InvalidAction { turn: 3, .. })
+        ));
+
+        let result = tamper(&|output| {
+            output.winner = match output.winner {
+                Some(Team::Red) => Some(Team::Blue),
+                _ => Some(Team::Red),
+            }
+        });
+        assert!(matches!(result, Err(VerifyError::WinnerMismatch)));
+
+        let result = tamper(&|output| output.stats.get_mut(&Team::Red).unwrap().kills += 1);
+        assert!(matches!(result, Err(VerifyError::StatsMismatch)));
+    }
+
+    #[test]
+    fn delta_replays_are_lossless() {
+        let output = play(config());
+        let replay = DeltaReplay::from(
+            serde_json::from_value::<MainOutput>(serde_json::to_value(&output).unwrap()).unwrap(),
+        );
+
+        // make sure the match exercises every kind of change
+        let changes = || replay.turns.iter().map(|turn| &turn.changes);
+        assert!(changes().any(|diff| !diff.moved.is_empty()));
+        assert!(changes().any(|diff| !diff.died.is_empty()));
+        assert!(changes().skip(1).any(|diff| diff
+            .spawned
+            .iter()
+            .any(|obj| matches!(obj.details(), ObjDetails::Unit(_)))));
+        assert!(changes().any(|diff| diff
+            .spawned
+            .iter()
+            .any(|obj| matches!(obj.details(), ObjDetails::Terrain(_)))));
+        let walls = replay
+            .initial_objs
+            .iter()
+            .filter(|(_, obj)| matches!(obj.details(), ObjDetails::Terrain(_)))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        assert!(changes().any(|diff| diff.health.keys().any(|id| walls.contains(id))));
+
+        let restored = MainOutput::from(replay);
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::to_value(&restored).unwrap()
+        );
+    }
+}
@@ -1,12 +1,12 @@
 use std::collections::{BTreeMap, HashMap};
-use std::ops::Add;
+use std::ops::{Add, AddAssign};
 use std::time::Duration;
 
 use maybe_owned::MaybeOwned;
-use rand::rngs::StdRng;
 use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use serde_with::{serde_as, DeserializeFromStr, SerializeDisplay};
 use strum::*;
 use thiserror::Error;
 
@@ -15,31 +15,167 @@ pub enum GameMode {
     Normal,
     NormalHeal,
     Hill,
+    /// Each team has a flag at its base, and scores by bringing an enemy flag back to its own base
+    CaptureTheFlag,
+    /// Walls close in from the edge of the grid on the schedule in `Settings::shrink`, and the last
+    /// team with units left wins
+    BattleRoyale,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
 pub enum MapType {
     Rect,
+    #[default]
     Circle,
+    /// No generated walls; the map is defined entirely by `grid_init` and `spawn_points`
+    Custom,
+}
+
+/// The directions that units can move, attack and heal in
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum DirectionSet {
+    /// North, south, east and west
+    #[default]
+    Four,
+    /// The four directions plus the diagonals
+    Eight,
+}
+
+/// How to pick the unit that gets to move when several units try to move into the same cell
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum ConflictPolicy {
+    /// Units moving north win, then east, south and west, then the diagonals in the same order
+    #[default]
+    DirectionPriority,
+    /// A unit picked at random, based on the match's seed
+    Random,
+    /// None of the units move
+    NobodyMoves,
+    /// The unit with the most health wins, with ties broken by direction priority
+    HealthiestWins,
+}
+
+/// When a match ends early because at most one team has units left, see
+/// `Settings::end_on_elimination`
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum EndOnElimination {
+    /// The match is always played to the turn limit
+    #[default]
+    Off,
+    /// The match ends right away, even if a later spawn wave would bring units back
+    Immediate,
+    /// The match ends once no spawn wave before the turn limit could bring units back, so the
+    /// result can't change anymore
+    WhenDecided,
+}
+
+/// Limits what each team's programs see of the board to what is near its own units
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FogOfWar {
+    /// How far, in cells, each unit can see. Distances are measured in a straight line between
+    /// cell centers.
+    pub vision_radius: usize,
+    /// Whether walls hide what is behind them
+    pub walls_block: bool,
+}
+
+impl Default for FogOfWar {
+    fn default() -> FogOfWar {
+        FogOfWar {
+            vision_radius: 5,
+            walls_block: true,
+        }
+    }
+}
+
+/// Resources and spawning for matches where teams buy their units. Spawn waves still come every
+/// `SpawnSettings::spawn_every` turns, but instead of `recurrent_unit_num` units of random types,
+/// each team gets the units in its spawn order that it can pay for, on its own share of the spawn
+/// points.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EconomySettings {
+    pub starting_resources: usize,
+    /// The resources that one `Harvest` action gathers
+    pub harvest_amount: usize,
+}
+
+impl Default for EconomySettings {
+    fn default() -> EconomySettings {
+        EconomySettings {
+            starting_resources: 10,
+            harvest_amount: 1,
+        }
+    }
 }
 
+/// The schedule of the closing walls in `GameMode::BattleRoyale`. The outermost ring of cells is
+/// walled off at the start of `start_turn`, and every `shrink_every` turns after that the next
+/// ring inward is, until only the middle `min_size` by `min_size` square is left open.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ShrinkSettings {
+    pub start_turn: usize,
+    pub shrink_every: usize,
+    pub min_size: usize,
+    /// Units standing on a cell as it's walled off die. With `damage`, once the first ring has
+    /// closed, units standing on the next ring to close also take this much damage every turn.
+    pub damage: Option<usize>,
+}
+
+impl Default for ShrinkSettings {
+    fn default() -> ShrinkSettings {
+        ShrinkSettings {
+            start_turn: 20,
+            shrink_every: 10,
+            min_size: 5,
+            damage: None,
+        }
+    }
+}
+
+/// A team in a match, identified by its index. The first `Team::NAMES.len()` teams are
+/// (de)serialized by name, and any others as `Team<index>`.
 #[derive(
-    Serialize,
-    Deserialize,
-    EnumString,
-    IntoStaticStr,
-    Debug,
-    PartialEq,
-    Eq,
-    Hash,
-    Copy,
-    Clone,
-    PartialOrd,
-    Ord,
+    SerializeDisplay, DeserializeFromStr, Debug, PartialEq, Eq, Hash, Copy, Clone, PartialOrd, Ord,
 )]
-pub enum Team {
-    Red,
-    Blue,
+pub struct Team(pub u8);
+
+#[allow(non_upper_case_globals)]
+impl Team {
+    pub const Red: Team = Team(0);
+    pub const Blue: Team = Team(1);
+    pub const Green: Team = Team(2);
+    pub const Yellow: Team = Team(3);
+
+    pub const NAMES: [&'static str; 4] = ["Red", "Blue", "Green", "Yellow"];
+}
+
+impl std::fmt::Display for Team {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match Self::NAMES.get(self.0 as usize) {
+            Some(name) => f.write_str(name),
+            None => write!(f, "Team{}", self.0),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid team name {0:?}")]
+pub struct ParseTeamError(String);
+
+impl std::str::FromStr for Team {
+    type Err = ParseTeamError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(i) = Self::NAMES.iter().position(|&name| name == s) {
+            return Ok(Team(i as u8));
+        }
+        s.strip_prefix("Team")
+            .and_then(|i| i.parse().ok())
+            .map(Team)
+            .ok_or_else(|| ParseTeamError(s.to_owned()))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,6 +183,69 @@ pub struct MainOutput {
     pub winner: Option<Team>,
     pub errors: BTreeMap<Team, ProgramError>,
     pub turns: Vec<CallbackInput>,
+    /// The settings the match was played with, so that replays can recreate the map
+    #[serde(default)]
+    pub settings: Settings,
+    /// The seed the match was played with, so that it can be verified with `replay::verify`
+    #[serde(default)]
+    pub seed: Option<String>,
+    #[serde(default)]
+    pub termination: Termination,
+    #[serde(default)]
+    pub stats: BTreeMap<Team, TeamStats>,
+}
+
+/// Totals for one team over a whole match
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct TeamStats {
+    pub units_spawned: usize,
+    /// Units that died, whether in combat, on lava, in the closing walls of
+    /// `GameMode::BattleRoyale`, or by standing on a spawn point when new units were spawned
+    pub units_lost: usize,
+    /// Enemy units that died from an attack by this team. Each unit counts as a kill for the team
+    /// whose attack took its last point of health.
+    pub kills: usize,
+    /// Health taken from enemy units by attacks, not counting damage that cover absorbed or that
+    /// went past a unit's remaining health
+    pub damage_dealt: usize,
+    /// Health lost to attacks (including ones by this team), lava and the closing walls
+    pub damage_received: usize,
+    /// Health restored by this team's units and by fountains that this team's units stand on, not
+    /// counting healing past a unit's maximum health
+    pub healing_done: usize,
+    /// Moves that didn't happen, because of a conflict, a blocked cell, or the edge of the grid
+    pub moves_blocked: usize,
+    pub invalid_actions: usize,
+    /// In `GameMode::Hill`, the number of turns that a unit of this team spent on the hill without
+    /// acting, summed over all units. This is the score that decides the winner.
+    pub hill_turns: usize,
+}
+
+impl AddAssign for TeamStats {
+    fn add_assign(&mut self, other: Self) {
+        self.units_spawned += other.units_spawned;
+        self.units_lost += other.units_lost;
+        self.kills += other.kills;
+        self.damage_dealt += other.damage_dealt;
+        self.damage_received += other.damage_received;
+        self.healing_done += other.healing_done;
+        self.moves_blocked += other.moves_blocked;
+        self.invalid_actions += other.invalid_actions;
+        self.hill_turns += other.hill_turns;
+    }
+}
+
+/// Why a match ended
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum Termination {
+    /// The match was played up to its last turn
+    #[default]
+    TurnLimit,
+    /// At most one team had units left, and `Settings::end_on_elimination` was enabled
+    Elimination,
+    /// The programs of one or more teams errored
+    Error,
 }
 
 #[serde_as]
@@ -78,15 +277,70 @@ pub struct CallbackInput {
     pub logs: BTreeMap<Team, Vec<String>>,
     pub debug_inspect_tables: BTreeMap<Id, DebugTable>,
     pub debug_locate_queries: BTreeMap<Team, Vec<Id>>,
+    #[serde(default)]
+    pub move_conflicts: Vec<MoveConflict>,
+    /// The units each team could see at the start of the turn, when fog of war is enabled
+    #[serde(default)]
+    pub visible_units: BTreeMap<Team, Vec<Id>>,
+    /// Each team's hill score at the start of the turn
+    #[serde(default)]
+    pub hill_score: BTreeMap<Team, usize>,
+    #[serde(default)]
+    pub flags: BTreeMap<Team, Flag>,
+    #[serde(default)]
+    pub captures: BTreeMap<Team, usize>,
+    /// Each team's resources at the start of the turn
+    #[serde(default)]
+    pub resources: BTreeMap<Team, usize>,
+    /// The spawn orders that teams gave this turn
+    #[serde(default)]
+    pub spawn_orders: BTreeMap<Team, Vec<UnitType>>,
+    #[serde(default)]
+    pub zone: Option<Zone>,
+}
+
+/// Several units trying to move into the same cell on the same turn
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MoveConflict {
+    pub coords: Coords,
+    /// The units that tried to move into the cell, sorted by id
+    pub contenders: Vec<Id>,
+    /// The unit that was picked to move, if any. Its move can still fail, e.g. if the cell is
+    /// taken by a unit that stays put.
+    pub winner: Option<Id>,
 }
 
 pub type ObjMap = BTreeMap<Id, Obj>;
 
 type GridMapType = HashMap<Coords, Id>;
 
+/// A sparse `Coords -> Id` lookup for a square grid of side `size`. The size is only used when
+/// serializing to the 2D array that runners receive; maps built with `collect()` have a size of 0
+/// until `with_size` is called.
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 #[serde(from = "SerdeGridMap", into = "SerdeGridMap")]
-pub struct GridMap(GridMapType);
+pub struct GridMap {
+    map: GridMapType,
+    size: usize,
+}
+
+impl GridMap {
+    pub fn new(size: usize) -> Self {
+        Self {
+            map: GridMapType::new(),
+            size,
+        }
+    }
+
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
 
 impl From<&ObjMap> for GridMap {
     fn from(obj_map: &ObjMap) -> Self {
@@ -100,16 +354,77 @@ impl From<&ObjMap> for GridMap {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct State {
     pub objs: ObjMap,
+    /// The units and walls, i.e. the objects that block movement
     pub grid: GridMap,
+    /// The terrain that units can stand on, like swamps
+    pub terrain_grid: GridMap,
     /// Should be sorted
     pub spawn_points: Vec<Coords>,
     pub settings: Settings,
+    /// All the teams participating in the match
+    pub teams: Vec<Team>,
+    pub ids: IdAllocator,
     #[serde(skip, default = "init_rng")]
-    pub rng: StdRng,
+    pub rng: GameRng,
+    /// The points each team has scored so far in `GameMode::Hill`
+    pub hill_score: BTreeMap<Team, usize>,
+    /// The flag of each team in `GameMode::CaptureTheFlag`, and empty in the other modes
+    pub flags: BTreeMap<Team, Flag>,
+    /// How many enemy flags each team has captured so far
+    pub captures: BTreeMap<Team, usize>,
+    /// The resources each team has to spend, in matches with `Settings::economy`
+    pub resources: BTreeMap<Team, usize>,
+    /// The units each team has ordered for the next spawn wave
+    pub spawn_orders: BTreeMap<Team, Vec<UnitType>>,
+    /// The closing walls in `GameMode::BattleRoyale`, and `None` in the other modes
+    pub zone: Option<Zone>,
+}
+
+/// A team's flag in `GameMode::CaptureTheFlag`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Flag {
+    /// Where the flag starts, and where the team's units bring enemy flags to capture them
+    pub base: Coords,
+    /// Where the flag is. While a unit carries it, that's where the unit is.
+    pub coords: Coords,
+    pub carrier: Option<Id>,
+}
+
+/// How far the walls of `GameMode::BattleRoyale` have closed in. Cells are grouped into rings by
+/// their distance from the edge of the grid, so ring 0 is the outermost one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Zone {
+    /// Every ring below this one has been walled off
+    pub closed_rings: usize,
+    /// The turns on which the remaining rings will be walled off, outermost first
+    pub upcoming: Vec<usize>,
+}
+
+/// Hands out the ids for the objects of a single match, so that concurrent matches don't interfere
+/// with each other and a match always gets the same ids.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdAllocator(usize);
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+impl IdAllocator {
+    pub fn new_id(&mut self) -> Id {
+        let id = Id(self.0);
+        self.0 += 1;
+        id
+    }
 }
 
-pub fn init_rng() -> StdRng {
-    StdRng::from_rng(rand::thread_rng()).unwrap()
+/// The RNG used for everything random in a match. Unlike `StdRng`, its algorithm is fixed, so a
+/// seed produces the same match on every platform and Rust/`rand` version.
+pub type GameRng = ChaCha12Rng;
+
+pub fn init_rng() -> GameRng {
+    GameRng::from_rng(rand::thread_rng()).unwrap()
 }
 
 pub type GridInitType = Vec<InitObj>;
@@ -119,6 +434,16 @@ pub struct SpawnSettings {
     pub initial_unit_num: usize,
     pub recurrent_unit_num: usize,
     pub spawn_every: usize,
+    /// The types that spawned units are picked from at random. A type can be listed more than once
+    /// to make it more likely. Every team gets the same type at mirrored spawn points.
+    #[serde(default = "SpawnSettings::default_unit_types")]
+    pub unit_types: Vec<UnitType>,
+}
+
+impl SpawnSettings {
+    fn default_unit_types() -> Vec<UnitType> {
+        vec![UnitType::Soldier]
+    }
 }
 
 impl Default for SpawnSettings {
@@ -127,14 +452,47 @@ impl Default for SpawnSettings {
             initial_unit_num: 4,
             recurrent_unit_num: 4,
             spawn_every: 10,
+            unit_types: Self::default_unit_types(),
         }
     }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
 pub struct Settings {
     pub grid_init: GridInitType,
     pub spawn_settings: Option<SpawnSettings>,
+    /// The width and height of the (square) grid. Must be odd so that the map has a center cell.
+    pub grid_size: usize,
+    /// The shape of the walls generated around the edge of the map
+    pub map_type: MapType,
+    /// Overrides the spawn points derived from `map_type`. Must be symmetric around the center of
+    /// the grid: point-symmetric for 2 teams, and under 90 degree rotations for 3 or 4 teams.
+    pub spawn_points: Option<Vec<Coords>>,
+    /// Overrides the default 3x3 hill in the center of the grid
+    pub hill: Option<Vec<Coords>>,
+    /// In `GameMode::CaptureTheFlag`, overrides the base of the first team, which defaults to the
+    /// middle of the west side of the grid. The bases of the other teams are its rotations, and
+    /// the match is rejected if they overlap or if one of them is on blocking terrain.
+    pub flag_base: Option<Coords>,
+    pub unit_stats: UnitStatsTable,
+    pub directions: DirectionSet,
+    pub move_conflicts: ConflictPolicy,
+    /// Without fog of war, every team sees the whole board
+    pub fog_of_war: Option<FogOfWar>,
+    /// With an economy, teams harvest resources and buy the units of every spawn wave after the
+    /// first one. It needs `spawn_settings`.
+    pub economy: Option<EconomySettings>,
+    /// When the walls close in, in `GameMode::BattleRoyale`
+    pub shrink: ShrinkSettings,
+    /// Makes the blocking terrain of the given types destructible, starting with the given health.
+    /// Every attack that reaches it takes its full attack power off, as `Cover` only protects
+    /// units, and the damage isn't counted in `TeamStats`. A cell opens up once its health runs
+    /// out. The walls that close in during `GameMode::BattleRoyale` can't be destroyed.
+    pub wall_health: BTreeMap<TerrainType, usize>,
+    /// In the normal game modes and `GameMode::BattleRoyale`, whether to end the match early once
+    /// at most one team has units left
+    pub end_on_elimination: EndOnElimination,
 }
 
 impl Default for Settings {
@@ -142,10 +500,45 @@ impl Default for Settings {
         Settings {
             grid_init: Default::default(),
             spawn_settings: Some(Default::default()),
+            grid_size: crate::GRID_SIZE,
+            map_type: Default::default(),
+            spawn_points: None,
+            hill: None,
+            flag_base: None,
+            unit_stats: Default::default(),
+            directions: Default::default(),
+            move_conflicts: Default::default(),
+            fog_of_war: None,
+            economy: None,
+            shrink: Default::default(),
+            wall_health: BTreeMap::new(),
+            end_on_elimination: Default::default(),
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Error, Debug, Clone)]
+pub enum SettingsError {
+    #[error("Grid size must be an odd number between {min} and {max}, got {0}", min = crate::MIN_GRID_SIZE, max = crate::MAX_GRID_SIZE)]
+    InvalidGridSize(usize),
+    #[error("Coordinates {0:?} are outside of the grid")]
+    OutOfBounds(Coords),
+    #[error("Spawn point {0:?} has no mirrored spawn point")]
+    AsymmetricSpawn(Coords),
+    #[error("Flag base {0:?} must be on open ground, and not be shared between teams")]
+    InvalidFlagBase(Coords),
+    #[error("A match must have between 1 and {max} teams, got {0}", max = crate::MAX_TEAMS)]
+    InvalidTeamCount(usize),
+    #[error("Spawn settings must list at least one unit type")]
+    NoUnitTypes,
+    #[error("An economy needs spawn settings to buy units at")]
+    EconomyWithoutSpawns,
+    #[error("The stats of {0:?} must have a health of at least 1 and no more than max_health")]
+    InvalidUnitStats(UnitType),
+    #[error("Only blocking terrain can be given health, and it must be at least 1, got {0:?}")]
+    InvalidWallHealth(TerrainType),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct StateForOutput {
     pub objs: ObjMap,
@@ -158,6 +551,7 @@ pub type TeamMap = BTreeMap<Team, Vec<Id>>;
 pub struct StateForProgramInput<'a> {
     pub objs: MaybeOwned<'a, ObjMap>,
     pub grid: MaybeOwned<'a, GridMap>,
+    pub terrain_grid: MaybeOwned<'a, GridMap>,
     pub teams: TeamMap,
     pub turn: usize,
 }
@@ -168,6 +562,27 @@ pub struct ProgramInput<'a> {
     pub state: StateForProgramInput<'a>,
     pub grid_size: usize,
     pub team: Team,
+    pub unit_stats: MaybeOwned<'a, UnitStatsTable>,
+    pub directions: DirectionSet,
+    /// The cells where units spawn
+    pub spawn_points: MaybeOwned<'a, Vec<Coords>>,
+    /// The cells that units score on in `GameMode::Hill`
+    pub hill: Vec<Coords>,
+    pub hill_score: BTreeMap<Team, usize>,
+    pub flags: BTreeMap<Team, FlagForProgramInput>,
+    pub captures: BTreeMap<Team, usize>,
+    /// Under fog of war, only the team's own resources
+    pub resources: BTreeMap<Team, usize>,
+    pub zone: Option<Zone>,
+}
+
+/// A `Flag` as a team sees it. Under fog of war, the carrier of a flag and where it is are hidden
+/// while the carrier is out of sight.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlagForProgramInput {
+    pub base: Coords,
+    pub coords: Option<Coords>,
+    pub carrier: Option<Id>,
 }
 
 pub type Range = (usize, Option<usize>);
@@ -187,7 +602,7 @@ pub struct Error {
 
 pub type DebugTable = HashMap<String, String>;
 
-#[derive(Serialize, Deserialize, Error, Debug)]
+#[derive(Serialize, Deserialize, Error, Debug, Clone)]
 pub enum ProgramError {
     #[error("Unhandled program error")]
     InternalError,
@@ -224,6 +639,10 @@ pub struct ProgramOutput {
     pub logs: Vec<String>,
     pub debug_inspect_tables: BTreeMap<Id, DebugTable>,
     pub debug_locate_queries: Vec<Id>,
+    /// In matches with `Settings::economy`, the units to buy at the next spawn wave, in order of
+    /// priority. Replaces the order given on an earlier turn, while `None` keeps it.
+    #[serde(default)]
+    pub spawn_order: Option<Vec<UnitType>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Copy, Clone, PartialOrd, Ord)]
@@ -240,28 +659,26 @@ impl Add for Coords {
 impl Add<Direction> for Coords {
     type Output = Self;
 
+    /// Steps one cell in the direction `rhs`. A step past the top or left edge of the grid leaves
+    /// the coordinates unchanged, rather than clamping one axis and turning a diagonal step into a
+    /// straight one.
     fn add(self, rhs: Direction) -> Self {
         let (dir_x, dir_y) = rhs.to_tuple();
-        Self(
-            if dir_x < 0 {
-                self.0.saturating_sub(dir_x.abs() as usize)
-            } else {
-                self.0 + dir_x as usize
-            },
-            if dir_y < 0 {
-                self.1.saturating_sub(dir_y.abs() as usize)
-            } else {
-                self.1 + dir_y as usize
-            },
-        )
+        match (
+            self.0.checked_add_signed(dir_x),
+            self.1.checked_add_signed(dir_y),
+        ) {
+            (Some(x), Some(y)) => Self(x, y),
+            _ => self,
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(from = "SerdeObj", into = "SerdeObj")]
 pub struct Obj(pub BasicObj, pub ObjDetails);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct BasicObj {
     pub id: Id,
     pub coords: Coords,
@@ -271,25 +688,41 @@ pub struct BasicObj {
 #[serde(from = "SerdeInitObj", into = "SerdeInitObj")]
 pub struct InitObj(pub Coords, pub ObjDetails);
 
-#[derive(Serialize, Deserialize, IntoStaticStr, Debug, Clone)]
+#[derive(Serialize, Deserialize, IntoStaticStr, Debug, Clone, PartialEq)]
 #[serde(tag = "obj_type")]
 pub enum ObjDetails {
     Terrain(Terrain),
     Unit(Unit),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Terrain {
     #[serde(rename = "type")]
     pub type_: TerrainType,
+    /// The health left, for terrain that units can destroy (see `Settings::wall_health`)
+    #[serde(default)]
+    pub health: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, IntoStaticStr, Debug, PartialEq, Copy, Clone)]
+#[derive(
+    Serialize, Deserialize, IntoStaticStr, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone,
+)]
 pub enum TerrainType {
     Wall,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+    /// Units standing in a swamp move half as often as usual
+    Swamp,
+    /// Heals the unit standing on it at the end of every turn
+    Fountain,
+    /// Damages the unit standing on it at the end of every turn
+    Lava,
+    /// Protects the unit standing on it from some of the damage of attacks
+    Cover,
+    /// Blocks movement like a wall, but units next to it can harvest it in matches with
+    /// `Settings::economy`
+    Resource,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Unit {
     #[serde(rename = "type")]
     pub type_: UnitType,
@@ -297,9 +730,79 @@ pub struct Unit {
     pub health: usize,
 }
 
-#[derive(Serialize, Deserialize, IntoStaticStr, Debug, PartialEq, Copy, Clone)]
+#[derive(
+    Serialize, Deserialize, IntoStaticStr, Debug, PartialEq, Eq, Hash, Copy, Clone, PartialOrd, Ord,
+)]
 pub enum UnitType {
     Soldier,
+    /// Attacks from a distance
+    Archer,
+    /// Has a lot of health, but moves slowly
+    Tank,
+    /// Heals other units in every game mode, but can't attack
+    Medic,
+}
+
+/// What a type of unit can do
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnitStats {
+    /// The health that units spawn with
+    pub health: usize,
+    /// The most that units can be healed to
+    pub max_health: usize,
+    pub attack_power: usize,
+    /// How many cells away an attack can reach. It hits the first object in its direction, so
+    /// units and walls in between block it.
+    pub attack_range: usize,
+    pub heal_power: usize,
+    /// Units can only move on every `move_every`-th turn, starting with the first one
+    pub move_every: usize,
+    pub actions: Vec<ActionType>,
+    /// The resources a team spends to spawn a unit, in matches with `Settings::economy`
+    pub cost: usize,
+}
+
+/// The stats of every unit type. Types that are left out when deserializing keep their default
+/// stats, so settings only need to list the types they change.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct UnitStatsTable(BTreeMap<UnitType, UnitStats>);
+
+impl Default for UnitStatsTable {
+    fn default() -> Self {
+        Self(
+            UnitType::ALL
+                .iter()
+                .map(|&type_| (type_, type_.default_stats()))
+                .collect(),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for UnitStatsTable {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut table = Self::default();
+        table.0.extend(BTreeMap::deserialize(deserializer)?);
+        Ok(table)
+    }
+}
+
+impl std::ops::Index<UnitType> for UnitStatsTable {
+    type Output = UnitStats;
+    fn index(&self, type_: UnitType) -> &UnitStats {
+        // every type is in the table, see `Default` and `Deserialize`
+        &self.0[&type_]
+    }
+}
+
+impl UnitStatsTable {
+    pub fn iter(&self) -> impl Iterator<Item = (&UnitType, &UnitStats)> {
+        self.0.iter()
+    }
+
+    pub fn insert(&mut self, type_: UnitType, stats: UnitStats) {
+        self.0.insert(type_, stats);
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
@@ -314,6 +817,8 @@ pub enum ActionType {
     Move,
     Attack,
     Heal,
+    /// Gathers resources from a resource node, in matches with `Settings::economy`
+    Harvest,
 }
 
 #[derive(Serialize, Deserialize, EnumString, Debug, PartialEq, Copy, Clone)]
@@ -322,6 +827,11 @@ pub enum Direction {
     South,
     East,
     West,
+    /// Only available with `DirectionSet::Eight`
+    NorthEast,
+    SouthEast,
+    SouthWest,
+    NorthWest,
 }
 
 impl Direction {
@@ -332,6 +842,10 @@ impl Direction {
             North => (0, -1),
             East => (1, 0),
             South => (0, 1),
+            NorthEast => (1, -1),
+            SouthEast => (1, 1),
+            SouthWest => (-1, 1),
+            NorthWest => (-1, -1),
         }
     }
 
@@ -342,8 +856,17 @@ impl Direction {
             North => South,
             East => West,
             South => North,
+            NorthEast => SouthWest,
+            SouthEast => NorthWest,
+            SouthWest => NorthEast,
+            NorthWest => SouthEast,
         }
     }
+
+    pub fn is_diagonal(self) -> bool {
+        let (x, y) = self.to_tuple();
+        x != 0 && y != 0
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -392,10 +915,10 @@ pub struct SerdeGridMap(SerdeGridMapType);
 
 impl From<GridMap> for SerdeGridMap {
     fn from(map: GridMap) -> Self {
-        let arr2d = (0..crate::GRID_SIZE)
+        let arr2d = (0..map.size)
             .map(|i| {
-                (0..crate::GRID_SIZE)
-                    .map(|j| map.0.get(&Coords(j, i)).copied())
+                (0..map.size)
+                    .map(|j| map.map.get(&Coords(j, i)).copied())
                     .collect()
             })
             .collect();
@@ -413,6 +936,7 @@ impl IntoIterator for SerdeGridMap {
 
 impl From<SerdeGridMap> for GridMap {
     fn from(map: SerdeGridMap) -> Self {
+        let size = map.0.len();
         let map = map
             .0
             .into_iter()
@@ -420,39 +944,42 @@ impl From<SerdeGridMap> for GridMap {
             .map(|(i, v)| {
                 v.into_iter()
                     .enumerate()
-                    .filter_map(move |(j, elem)| elem.map(|elem| (Coords(i, j), elem)))
+                    .filter_map(move |(j, elem)| elem.map(|elem| (Coords(j, i), elem)))
             })
             .flatten()
             .collect();
-        Self(map)
+        Self { map, size }
     }
 }
 
 impl std::ops::Deref for GridMap {
     type Target = GridMapType;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.map
     }
 }
 impl std::ops::DerefMut for GridMap {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.map
     }
 }
 impl std::iter::FromIterator<(Coords, Id)> for GridMap {
     fn from_iter<T: IntoIterator<Item = (Coords, Id)>>(iter: T) -> Self {
-        Self(iter.into_iter().collect())
+        Self {
+            map: iter.into_iter().collect(),
+            size: 0,
+        }
     }
 }
 impl Extend<(Coords, Id)> for GridMap {
     fn extend<T: IntoIterator<Item = (Coords, Id)>>(&mut self, iter: T) {
-        self.0.extend(iter);
+        self.map.extend(iter);
     }
 }
 impl IntoIterator for GridMap {
     type Item = (Coords, Id);
     type IntoIter = <GridMapType as IntoIterator>::IntoIter;
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.map.into_iter()
     }
 }